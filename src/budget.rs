@@ -0,0 +1,123 @@
+use std::sync::{Arc, Mutex, Condvar};
+use ffmpeg::frame::Video;
+
+// memory accounting for concurrently processed videos. decoded frames reserve their size
+// until they are dropped, i.e. while they are queued in the pipeline or held by a stitcher.
+// once the budget is used up only the video that was admitted first may allocate more,
+// the others wait. it never waits for anyone, so it finishes and frees its share eventually.
+
+pub struct Budget {
+    limit: usize,
+    state: Mutex<State>,
+    changed: Condvar
+}
+
+struct State {
+    used: usize,
+    next_id: u64,
+    /// admitted videos, oldest first
+    jobs: Vec<u64>
+}
+
+/// A video being processed, holds a place in the admission order until dropped
+pub struct Job {
+    id: u64,
+    budget: Arc<Budget>
+}
+
+/// Bytes accounted to the budget until dropped
+pub struct Reservation {
+    bytes: usize,
+    budget: Arc<Budget>
+}
+
+impl Budget {
+    pub fn new(limit: usize) -> Arc<Budget> {
+        Arc::new(Budget {limit, state: Mutex::new(State {used: 0, next_id: 0, jobs: vec![]}), changed: Condvar::new()})
+    }
+
+    pub fn used(&self) -> usize {
+        self.state.lock().unwrap().used
+    }
+
+    /// waits until there is room for another video
+    pub fn admit(budget: &Arc<Budget>) -> Job {
+        let mut state = budget.state.lock().unwrap();
+        while state.used >= budget.limit && !state.jobs.is_empty() {
+            state = budget.changed.wait(state).unwrap();
+        }
+
+        let id = state.next_id;
+        state.next_id += 1;
+        state.jobs.push(id);
+
+        Job {id, budget: budget.clone()}
+    }
+
+    fn release(&self, bytes: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.used -= bytes;
+        self.changed.notify_all();
+    }
+}
+
+impl Job {
+    /// waits until `bytes` fit into the budget, unless this is the oldest job
+    pub fn reserve(&self, bytes: usize) -> Reservation {
+        let budget = &self.budget;
+        let mut state = budget.state.lock().unwrap();
+        while state.used + bytes > budget.limit && state.jobs.first() != Some(&self.id) {
+            state = budget.changed.wait(state).unwrap();
+        }
+        state.used += bytes;
+
+        Reservation {bytes, budget: budget.clone()}
+    }
+
+    pub fn reserve_frame(&self, frame: &Video) -> Reservation {
+        self.reserve(frame_bytes(frame))
+    }
+}
+
+impl Drop for Job {
+    fn drop(&mut self) {
+        let mut state = self.budget.state.lock().unwrap();
+        let id = self.id;
+        state.jobs.retain(|j| *j != id);
+        self.budget.changed.notify_all();
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.release(self.bytes);
+    }
+}
+
+pub fn frame_bytes(frame: &Video) -> usize {
+    (0..frame.planes()).map(|i| frame.data(i).len()).sum()
+}
+
+#[cfg(test)]
+mod test {
+    use super::Budget;
+
+    #[test]
+    fn oldest_may_exceed() {
+        let budget = Budget::new(100);
+        let first = Budget::admit(&budget);
+        let second = Budget::admit(&budget);
+
+        {
+            let _a = second.reserve(60);
+            // over the limit, but nobody else could free memory for the oldest job
+            let _b = first.reserve(60);
+            assert_eq!(budget.used(), 120);
+        }
+        assert_eq!(budget.used(), 0);
+
+        drop(first);
+        let _c = second.reserve(150);
+        assert_eq!(budget.used(), 150);
+    }
+}
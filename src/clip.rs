@@ -0,0 +1,162 @@
+use std::path::Path;
+use ffmpeg;
+use ffmpeg::Rescale;
+use ffmpeg::codec::threading;
+use ffmpeg::frame::Video;
+use ffmpeg::software::converter;
+use ffmpeg::util::format::pixel::Pixel;
+use error::{self, Error};
+
+// video clips of the detected sequences. FFV1 in Matroska is lossless and keeps the pixel format
+// of the source, H264 in MP4 is high quality and plays everywhere. both write the frames as they are
+// in the video or stabilized, i.e. placed on the composite canvas
+arg_enum!{
+    #[derive(Copy, Clone, PartialEq)]
+    pub enum Clip {
+        FFV1, H264
+    }
+}
+
+impl Clip {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Clip::FFV1 => "mkv",
+            Clip::H264 => "mp4"
+        }
+    }
+
+    pub fn codec(self) -> &'static str {
+        match self {
+            Clip::FFV1 => "ffv1",
+            Clip::H264 => "libx264"
+        }
+    }
+
+    fn pixel_format(self, source: Pixel) -> Pixel {
+        match self {
+            // ffv1 only takes packed RGB with alpha in native order
+            Clip::FFV1 if source == Pixel::RGBA => Pixel::BGRA,
+            Clip::FFV1 => source,
+            Clip::H264 => Pixel::YUV420P
+        }
+    }
+
+    fn options(self) -> ffmpeg::Dictionary<'static> {
+        let mut options = ffmpeg::Dictionary::new();
+        match self {
+            Clip::FFV1 => options.set("level", "3"),
+            Clip::H264 => {
+                options.set("crf", "16");
+                options.set("preset", "slow");
+            }
+        }
+        options
+    }
+}
+
+/// The frames of a sequence as a video file, timestamps start at 0
+pub struct ClipOut {
+    octx: ffmpeg::format::context::Output,
+    encoder: ffmpeg::codec::encoder::video::Encoder,
+    conv: Option<ffmpeg::software::scaling::context::Context>,
+    time_base: ffmpeg::Rational,
+    frame_rate: ffmpeg::Rational,
+    /// pts of the first frame
+    first_pts: Option<i64>,
+    frames_written: i64,
+    /// relative to the output directory
+    pub file: String
+}
+
+impl ClipOut {
+    /// `time_base` of the pts passed to `encode`, None if the frames have no timestamps
+    pub fn open(dir: &Path, name: &str, clip: Clip, frame: &Video, sar: ffmpeg::Rational, time_base: Option<(i32, i32)>, fps: f64) -> Result<Self, Error> {
+        ClipOut::open_threaded(dir, name, clip, frame, sar, time_base, fps, threading::Type::Frame)
+    }
+
+    /// frame threading keeps a frame per thread queued, slice threading only the one being encoded
+    pub fn open_threaded(dir: &Path, name: &str, clip: Clip, frame: &Video, sar: ffmpeg::Rational, time_base: Option<(i32, i32)>, fps: f64, threads: threading::Type) -> Result<Self, Error> {
+        let frame_rate = ffmpeg::Rational::from(if fps > 0.0 { fps } else { 24.0 });
+        let time_base = time_base.map(ffmpeg::Rational::from).unwrap_or(frame_rate.invert());
+        let file = format!("{}.{}", name, clip.extension());
+        let path = dir.join(&file);
+        let mut octx = ffmpeg::format::output(&error::ffmpeg_path(&path)?)?;
+        let global_header = octx.format().flags().contains(ffmpeg::format::flag::GLOBAL_HEADER);
+
+        let codec = ffmpeg::encoder::find_by_name(clip.codec()).ok_or(format!("no {} encoder", clip.codec()))?;
+        let mut encoder = {
+            let mut output = octx.add_stream(codec)?;
+            output.set_time_base(time_base);
+            output.codec().encoder()
+        };
+
+        encoder.set_time_base(time_base);
+        encoder.set_threading(threading::Config{kind: threads, count: 0, safe: true});
+        if global_header {
+            encoder.set_flags(ffmpeg::codec::flag::GLOBAL_HEADER);
+        }
+
+        let pixel_format = clip.pixel_format(frame.format());
+        let mut encoder = encoder.video()?;
+        encoder.set_width(frame.width());
+        encoder.set_height(frame.height());
+        encoder.set_aspect_ratio(sar);
+        encoder.set_format(pixel_format);
+        let encoder = encoder.open_as_with(codec, clip.options())?;
+
+        let conv = if pixel_format != frame.format() {
+            Some(converter((frame.width(), frame.height()), frame.format(), pixel_format)?)
+        } else {
+            None
+        };
+
+        octx.write_header()?;
+
+        Ok(ClipOut {octx, encoder, conv, time_base, frame_rate, first_pts: None, frames_written: 0, file})
+    }
+
+    /// frames without timestamp are placed at the stream frame rate
+    pub fn encode(&mut self, frame: &Video, pts: Option<i64>) -> Result<(), Error> {
+        let first = *self.first_pts.get_or_insert(pts.unwrap_or(0));
+        let pts = match pts {
+            Some(p) => p - first,
+            None => self.frames_written.rescale(self.frame_rate.invert(), self.time_base)
+        };
+
+        let mut frame_out = if let Some(ref mut conv) = self.conv {
+            let mut frame_out = Video::new(self.encoder.format(), frame.width(), frame.height());
+            conv.run(frame, &mut frame_out)?;
+            frame_out
+        } else {
+            frame.clone()
+        };
+        frame_out.set_pts(Some(pts));
+
+        let mut packet = ffmpeg::codec::packet::packet::Packet::empty();
+        if self.encoder.encode(&frame_out, &mut packet)? {
+            self.write_packet(packet)?;
+        }
+        self.frames_written += 1;
+        Ok(())
+    }
+
+    fn write_packet(&mut self, mut packet: ffmpeg::packet::Packet) -> Result<(), Error> {
+        let stream_time_base = self.octx.stream(0).ok_or("clip without stream")?.time_base();
+        packet.set_stream(0);
+        packet.rescale_ts(self.time_base, stream_time_base);
+        packet.write_interleaved(&mut self.octx)?;
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> Result<(), Error> {
+        loop {
+            let mut packet = ffmpeg::codec::packet::packet::Packet::empty();
+            match self.encoder.flush(&mut packet) {
+                Ok(true) => self.write_packet(packet)?,
+                _ => break
+            }
+        }
+        self.octx.write_trailer()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,468 @@
+use std::collections::VecDeque;
+use motion::search::Estimate;
+use motion::vectors::MVec;
+use profile::Thresholds;
+
+// pan detection decisions, separated from decoding, motion search and encoding.
+// the detector only sees per-frame statistics, so recorded statistics can be fed
+// through it again without the video.
+
+/// What the detector needs to know about a frame
+#[derive(Copy, Clone, Debug)]
+pub struct FrameStats {
+    pub idx: u32,
+    /// previous frame that was not collapsed into a held frame
+    pub pred_idx: Option<u32>,
+    /// seconds since the predecessor
+    pub interval: Option<f64>,
+    /// spread between the 10th and 90th luma percentile
+    pub luma_spread: u8,
+    /// full frame comparison against the predecessor
+    pub estimate: Option<Estimate>
+}
+
+/// slack for summed up durations
+const EPSILON : f64 = 1e-6;
+
+impl FrameStats {
+    /// seconds since the predecessor, counted in decoded frames if there are no timestamps
+    fn duration(&self, frame_duration: f64) -> f64 {
+        match (self.interval, self.pred_idx) {
+            (Some(interval), _) => interval,
+            (None, Some(pred)) if pred < self.idx => (self.idx - pred) as f64 * frame_duration,
+            _ => frame_duration
+        }
+    }
+
+    /// predecessor motion estimate scaled to motion per `reference` seconds
+    fn velocity(&self, reference: Option<f64>) -> Option<MVec> {
+        self.estimate.map(|est| scaled_vector(&est, self.interval, reference))
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Run {
+    Still,
+    SceneChange,
+    Run {motion_frames: usize,oldest_frame: usize, end: RunEnd},
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum RunEnd {
+    OutOfFrames,
+    SceneChange,
+    LowEntropyFrame
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum PanEnd {
+    Scenechange,
+    QueueSaturation,
+    RunDiscontinuity(Run),
+    EndOfStream
+}
+
+/// Instructions for whoever holds the actual frames, to be carried out in order
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Action {
+    /// the oldest queued frame fell out of the queue without becoming part of a pan
+    Drop(u32),
+    /// start a pan at the given frame, queued frames older than it are discarded
+    Open {start_frame: u32, run: Run},
+    /// move the oldest queued frame into the open pan
+    Append(u32),
+    /// close the open pan
+    Close(PanEnd)
+}
+
+/// motion vector of `est` scaled from `interval` to `reference` seconds, for sources with variable frame timing
+pub fn scaled_vector(est: &Estimate, interval: Option<f64>, reference: Option<f64>) -> MVec {
+    match (interval, reference) {
+        (Some(i), Some(r)) if i > 0.0 && r > 0.0 => {
+            let scale = r / i;
+            MVec::new().from_vector((est.x as f64 * scale).round() as isize, (est.y as f64 * scale).round() as isize)
+        }
+        _ => MVec::new().from_vector(est.x, est.y)
+    }
+}
+
+/// estimates that move less than the still threshold in both directions
+fn is_still(est: &Estimate, still_motion_px: isize) -> bool {
+    est.x.abs() <= still_motion_px && est.y.abs() <= still_motion_px
+}
+
+pub struct Detector {
+    thresholds: Thresholds,
+    /// newest first
+    queue: VecDeque<FrameStats>,
+    /// last frame handed to the open pan, still takes part in run detection
+    pan_tail: Option<FrameStats>,
+    /// classification of the queue after the last push
+    run: Run,
+    /// seconds of motion in that run
+    run_duration: f64
+}
+
+impl Detector {
+    pub fn new(thresholds: Thresholds) -> Self {
+        Detector {thresholds, queue: VecDeque::new(), pan_tail: None, run: Run::Still, run_duration: 0.0}
+    }
+
+    pub fn last_run(&self) -> Run {
+        self.run
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.pan_tail.is_some()
+    }
+
+    pub fn push(&mut self, frame: FrameStats) -> Vec<Action> {
+        let mut actions = vec![];
+        let max_queue = self.thresholds.max_queue_duration;
+
+        self.queue.push_front(frame);
+
+        if self.queue_duration() > max_queue + EPSILON {
+            // a long held frame can overflow the queue of an open pan at once
+            self.close(PanEnd::QueueSaturation, &mut actions);
+            while self.queue.len() > 1 && self.queue_duration() > max_queue + EPSILON {
+                let dropped = self.queue.pop_back().unwrap();
+                actions.push(Action::Drop(dropped.idx));
+            }
+        }
+
+        // the frame was just pushed, the queue is never empty here
+        let mut oldest_queued_idx = self.queue[self.queue.len()-1].idx as usize;
+
+        if let Some(tail) = self.pan_tail {
+            if self.queue[self.queue.len()-1].pred_idx == Some(tail.idx) {
+                oldest_queued_idx = tail.idx as usize;
+            } else {
+                // frames went missing between the pan and the queue, e.g. a broken log. resync with a fresh pan
+                let run = self.run;
+                self.close(PanEnd::RunDiscontinuity(run), &mut actions);
+            }
+        }
+
+        let (run, _) = self.run_length();
+        match run {
+            Run::SceneChange => {
+                self.close(PanEnd::Scenechange, &mut actions);
+            }
+            Run::Run{oldest_frame, ..} => {
+                if oldest_frame != oldest_queued_idx {
+                    self.close(PanEnd::RunDiscontinuity(run), &mut actions);
+                }
+            }
+            _ => {}
+        }
+
+        if self.queue_duration() >= max_queue - EPSILON {
+            self.close(PanEnd::QueueSaturation, &mut actions);
+        }
+
+        let (run, duration) = self.run_length();
+        self.run = run;
+        self.run_duration = duration;
+
+        if let run @ Run::Run{..} = self.run {
+            self.try_open(run, &mut actions);
+
+            if self.is_open() {
+                while let Some(frame) = self.queue.pop_back() {
+                    actions.push(Action::Append(frame.idx));
+                    self.pan_tail = Some(frame);
+                }
+            }
+        }
+
+        actions
+    }
+
+    /// end of stream
+    pub fn finish(&mut self) -> Vec<Action> {
+        let mut actions = vec![];
+        self.close(PanEnd::EndOfStream, &mut actions);
+        actions
+    }
+
+    /// seconds of video in the queue
+    fn queue_duration(&self) -> f64 {
+        let frame_duration = self.thresholds.frame_duration;
+        self.queue.iter().map(|f| f.duration(frame_duration)).sum()
+    }
+
+    fn close(&mut self, reason: PanEnd, actions: &mut Vec<Action>) {
+        if self.pan_tail.take().is_some() {
+            actions.push(Action::Close(reason));
+        }
+    }
+
+    fn try_open(&mut self, run: Run, actions: &mut Vec<Action>) {
+        if self.is_open() {
+            return;
+        }
+
+        let (motion_frames, start_frame) = match run {
+            Run::Run{motion_frames, oldest_frame, ..} => (motion_frames, oldest_frame as u32),
+            _ => return
+        };
+
+        if motion_frames < self.thresholds.min_motion_frames as usize || self.run_duration < self.thresholds.min_pan_duration - EPSILON {
+            return;
+        }
+
+        while self.queue.back().map(|f| f.idx < start_frame).unwrap_or(false) {
+            self.queue.pop_back();
+        }
+
+        // the run starts at a queued frame, so one is left
+        let first = match self.queue.pop_back() {
+            Some(first) => first,
+            None => return
+        };
+        actions.push(Action::Open{start_frame, run});
+        actions.push(Action::Append(first.idx));
+        self.pan_tail = Some(first);
+    }
+
+    /// the run and how many seconds of it are in motion
+    fn run_length(&self) -> (Run, f64) {
+        let thresholds = self.thresholds;
+
+        /*
+         * goals
+         * - find some run of linear motion
+         * - extend scene around motion forwards and backwards through non-scene change still frames
+         * - be robust against I- and low-predicted P-frames
+         * - be robust against still frames/motion stutter
+         * - handle changes of direction as long as frames during directional change are highly predicted
+         *   e.g. if there is a gap in our motion knowledge look for continuity. otherwise look for motion + prediction between frames
+         */
+
+        let frame_refs : Vec<&FrameStats> = self.queue.iter().chain(self.pan_tail.iter()).collect();
+
+        if frame_refs.len() < 2 {
+            return (Run::Still, 0.0);
+        }
+
+        let mut motion_frames = 0;
+        let mut motion_duration = 0.0;
+        let mut last = frame_refs[0].idx as usize;
+        let mut end_reason = RunEnd::OutOfFrames;
+        let mut mvec;
+        // vectors get compared as motion per interval of the newest frame
+        let reference = frame_refs[0].interval;
+
+        {
+            let current = frame_refs[0];
+            let pred = frame_refs[1];
+
+            match current.estimate {
+                Some(est) => {
+                    if let Some(pred_est) = pred.estimate {
+                        if (pred_est.error_fraction() - est.error_fraction()).abs() > thresholds.scene_change_error_jump {
+                            return (Run::SceneChange, 0.0);
+                        }
+                    }
+                    mvec = scaled_vector(&est, current.interval, reference);
+                    if est.quantile(0.75) >= thresholds.mismatch_error_q75 {
+                        if let Some(vec) = pred.velocity(reference) {
+                            if !vec.is_similar(&mvec) {
+                                return (Run::SceneChange, 0.0);
+                            }
+                        } else {
+                            return (Run::SceneChange, 0.0);
+                        }
+                    }
+                    if is_still(&est, thresholds.still_motion_px) {
+                        return (Run::Still, 0.0);
+                    }
+                }
+                None => return (Run::Still, 0.0)
+            }
+        }
+
+        for frame_idx in 1..frame_refs.len() {
+            let newer = frame_refs[frame_idx - 1];
+            let current = frame_refs[frame_idx];
+
+            if current.luma_spread <= thresholds.min_luma_spread {
+                end_reason = RunEnd::LowEntropyFrame;
+                break;
+            }
+
+            // the newer frame's estimate is the comparison against the current one
+            let successor_estimate = match newer.estimate {
+                Some(est) => est,
+                None => break
+            };
+
+            let vec = scaled_vector(&successor_estimate, newer.interval, reference);
+            if successor_estimate.quantile(0.75) >= thresholds.mismatch_error_q75 && !vec.is_similar(&mvec) {
+                end_reason = RunEnd::SceneChange;
+                break
+            }
+
+            last = current.idx as usize;
+            if !is_still(&successor_estimate, thresholds.still_motion_px) {
+                mvec = vec;
+                motion_frames += 1;
+                motion_duration += newer.duration(thresholds.frame_duration);
+            }
+
+            // probably redundant given the same check above the loop
+            if let Some(predecessor_estimate) = current.estimate {
+                if (predecessor_estimate.error_fraction() - successor_estimate.error_fraction()).abs() > thresholds.scene_change_error_jump {
+                    end_reason = RunEnd::SceneChange;
+                    break;
+                }
+            }
+        }
+
+        if motion_frames == 0 {
+            return (Run::Still, 0.0);
+        }
+
+        (Run::Run{motion_frames, oldest_frame: last, end: end_reason}, motion_duration)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use profile::Profile;
+
+    fn detector() -> Detector {
+        Detector::new(Profile::default().thresholds(24.0, 1280, 720))
+    }
+
+    fn estimate(x: isize, y: isize, error: u8) -> Estimate {
+        let mut est = Estimate::still(1000);
+        est.x = x;
+        est.y = y;
+        est.error_sum = error as u64 * 1000;
+        est.histogram[error as usize] = 10;
+        est
+    }
+
+    fn frame(idx: u32, est: Option<Estimate>) -> FrameStats {
+        FrameStats {
+            idx,
+            pred_idx: if idx > 0 { Some(idx - 1) } else { None },
+            interval: None,
+            luma_spread: 100,
+            estimate: est
+        }
+    }
+
+    fn pan(idx: u32) -> FrameStats {
+        frame(idx, if idx > 0 { Some(estimate(4, 0, 2)) } else { None })
+    }
+
+    fn still(idx: u32) -> FrameStats {
+        frame(idx, Some(estimate(0, 0, 0)))
+    }
+
+    fn cut(idx: u32) -> FrameStats {
+        frame(idx, Some(estimate(-20, 0, 40)))
+    }
+
+    fn run(frames: &[FrameStats]) -> Run {
+        let mut d = detector();
+        for f in frames {
+            d.queue.push_front(*f);
+        }
+        d.run_length().0
+    }
+
+    /// pushes a pan of frames 0 ..= last, which opens at frame 6
+    fn open_pan(d: &mut Detector, last: u32) {
+        for i in 0 .. last + 1 {
+            let actions = d.push(pan(i));
+            if i == 6 {
+                assert_eq!(actions[0], Action::Open{start_frame: 0, run: Run::Run{motion_frames: 6, oldest_frame: 0, end: RunEnd::OutOfFrames}});
+                assert_eq!(&actions[1..], &(0 .. 7).map(Action::Append).collect::<Vec<_>>()[..]);
+            }
+        }
+        assert!(d.is_open());
+    }
+
+    #[test]
+    fn run_ends() {
+        let frames : Vec<_> = (0..4).map(pan).collect();
+        assert_eq!(run(&frames), Run::Run{motion_frames: 3, oldest_frame: 0, end: RunEnd::OutOfFrames});
+
+        let mut flat = frames.clone();
+        flat[1].luma_spread = 10;
+        assert_eq!(run(&flat), Run::Run{motion_frames: 1, oldest_frame: 2, end: RunEnd::LowEntropyFrame});
+
+        let mut cut_before = vec![pan(0), cut(1)];
+        cut_before.extend((2..5).map(pan));
+        assert_eq!(run(&cut_before), Run::Run{motion_frames: 3, oldest_frame: 1, end: RunEnd::SceneChange});
+
+        let mut cut_now : Vec<_> = (0..4).map(pan).collect();
+        cut_now.push(cut(4));
+        assert_eq!(run(&cut_now), Run::SceneChange);
+
+        let stills : Vec<_> = (0..4).map(still).collect();
+        assert_eq!(run(&stills), Run::Still);
+    }
+
+    #[test]
+    fn pan_ends() {
+        let mut d = detector();
+        open_pan(&mut d, 8);
+        assert_eq!(d.push(pan(9)), vec![Action::Append(9)]);
+        assert_eq!(d.finish(), vec![Action::Close(PanEnd::EndOfStream)]);
+        assert!(!d.is_open());
+
+        let mut d = detector();
+        open_pan(&mut d, 8);
+        assert_eq!(d.push(cut(9)), vec![Action::Close(PanEnd::Scenechange)]);
+
+        let mut d = detector();
+        open_pan(&mut d, 8);
+        let mut flat = still(9);
+        flat.luma_spread = 10;
+        assert_eq!(d.push(flat), vec![]);
+        assert_eq!(d.push(pan(10)), vec![]);
+        let run = Run::Run{motion_frames: 1, oldest_frame: 10, end: RunEnd::LowEntropyFrame};
+        assert_eq!(d.push(pan(11)), vec![Action::Close(PanEnd::RunDiscontinuity(run))]);
+
+        let mut d = detector();
+        open_pan(&mut d, 8);
+        for i in 9 .. 32 {
+            assert_eq!(d.push(still(i)), vec![]);
+        }
+        assert_eq!(d.push(still(32)), vec![Action::Close(PanEnd::QueueSaturation)]);
+        assert_eq!(d.push(still(33)), vec![Action::Drop(9)]);
+    }
+
+    #[test]
+    fn gap_after_pan_resyncs() {
+        let mut d = detector();
+        open_pan(&mut d, 8);
+        let gap = FrameStats {pred_idx: Some(5), ..pan(12)};
+        match d.push(gap).first() {
+            Some(&Action::Close(PanEnd::RunDiscontinuity(_))) => {},
+            other => panic!("expected the pan to close, got {:?}", other)
+        }
+        assert!(!d.is_open());
+    }
+
+    #[test]
+    fn held_frames_count_their_duration() {
+        // animated on twos: every other decoded frame was collapsed into its predecessor
+        let twos = |idx: u32| FrameStats {pred_idx: if idx > 0 { Some(idx - 2) } else { None }, ..pan(idx)};
+
+        let mut d = detector();
+        assert_eq!(d.push(twos(0)), vec![]);
+        assert_eq!(d.push(twos(2)), vec![]);
+        assert_eq!(d.push(twos(4)), vec![]);
+        // 3 steps of 2/24s are as long as 6 frames on ones
+        let actions = d.push(twos(6));
+        assert_eq!(actions[0], Action::Open{start_frame: 0, run: Run::Run{motion_frames: 3, oldest_frame: 0, end: RunEnd::OutOfFrames}});
+        assert_eq!(&actions[1..], &[Action::Append(0), Action::Append(2), Action::Append(4), Action::Append(6)]);
+    }
+}
@@ -0,0 +1,68 @@
+use std::fmt;
+use std::error::Error as StdError;
+use std::io;
+use std::path::{Path, PathBuf};
+use ffmpeg;
+
+/// Anything that can go wrong while processing a video. These end the affected sequence
+/// or video, the remaining inputs are still processed.
+#[derive(Debug)]
+pub enum Error {
+    Ffmpeg(ffmpeg::Error),
+    Io(PathBuf, io::Error),
+    /// ffmpeg only takes UTF-8 paths
+    NonUtf8Path(PathBuf),
+    Other(String)
+}
+
+impl Error {
+    pub fn io(path: &Path) -> impl FnOnce(io::Error) -> Error {
+        let path = path.to_owned();
+        move |e| Error::Io(path, e)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Ffmpeg(ref e) => write!(f, "ffmpeg: {}", e),
+            Error::Io(ref path, ref e) => write!(f, "{}: {}", path.display(), e),
+            Error::NonUtf8Path(ref path) => write!(f, "{}: path is not valid UTF-8", path.display()),
+            Error::Other(ref msg) => f.write_str(msg)
+        }
+    }
+}
+
+impl ::std::error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Ffmpeg(ref e) => e.description(),
+            Error::Io(_, ref e) => e.description(),
+            Error::NonUtf8Path(_) => "path is not valid UTF-8",
+            Error::Other(ref msg) => msg
+        }
+    }
+}
+
+impl From<ffmpeg::Error> for Error {
+    fn from(e: ffmpeg::Error) -> Self {
+        Error::Ffmpeg(e)
+    }
+}
+
+impl From<String> for Error {
+    fn from(msg: String) -> Self {
+        Error::Other(msg)
+    }
+}
+
+impl<'a> From<&'a str> for Error {
+    fn from(msg: &'a str) -> Self {
+        Error::Other(msg.to_owned())
+    }
+}
+
+/// the ffmpeg crate panics on paths it cannot convert, check them first
+pub fn ffmpeg_path(path: &Path) -> Result<&str, Error> {
+    path.to_str().ok_or_else(|| Error::NonUtf8Path(path.to_owned()))
+}
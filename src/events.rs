@@ -0,0 +1,202 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use serde_json;
+use motion::search::Estimate;
+use detect::{Detector, FrameStats, Action, Run, PanEnd};
+use profile::Profile;
+
+// --log writes one JSON object per line to <video>.seq/frames.jsonl,
+// distinguished by their "event" field. frame events carry everything the
+// pan detector looks at, which is what --replay feeds back into it.
+
+/// Summary of a luma or block error histogram
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Quantiles {
+    pub min: u8,
+    pub q10: u8,
+    pub q25: u8,
+    pub q50: u8,
+    pub q75: u8,
+    pub q90: u8,
+    pub max: u8,
+    pub avg: u32,
+    pub mode: u8
+}
+
+/// Full frame motion estimate against another frame
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Comparison {
+    /// index of the frame compared against
+    pub with: u32,
+    pub error_fraction: f32,
+    pub area_fraction: f32,
+    pub errors: Quantiles,
+    pub estimate: Estimate
+}
+
+impl Comparison {
+    pub fn new(with: u32, estimate: Estimate) -> Self {
+        Comparison {
+            with,
+            error_fraction: estimate.error_fraction(),
+            area_fraction: estimate.area_fraction(),
+            errors: Quantiles {
+                min: estimate.min(),
+                q10: estimate.quantile(0.1),
+                q25: estimate.quantile(0.25),
+                q50: estimate.quantile(0.5),
+                q75: estimate.quantile(0.75),
+                q90: estimate.quantile(0.9),
+                max: estimate.max(),
+                avg: estimate.avg(),
+                mode: estimate.mode()
+            },
+            estimate
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// first line of every log
+    Stream {fps: f64, width: u32, height: u32, profile: Profile},
+    Frame {
+        idx: u32,
+        pred_idx: Option<u32>,
+        pts: Option<i64>,
+        /// seconds
+        time: Option<f64>,
+        interval: Option<f64>,
+        frame_type: String,
+        repeats: u32,
+        luma: Quantiles,
+        comparisons: Vec<Comparison>
+    },
+    /// classification of the detector queue after a frame was added
+    Run {idx: u32, run: Run},
+    PanStart {start_frame: u32, run: Run},
+    PanEnd {reason: PanEnd, period: Option<(isize, isize)>}
+}
+
+impl Event {
+    /// what the pan detector saw of a frame event
+    fn frame_stats(&self) -> Option<FrameStats> {
+        match *self {
+            Event::Frame{idx, pred_idx, interval, luma, ref comparisons, ..} => Some(FrameStats {
+                idx,
+                pred_idx,
+                interval,
+                luma_spread: luma.q90 - luma.q10,
+                estimate: comparisons.iter().find(|c| Some(c.with) == pred_idx).map(|c| c.estimate)
+            }),
+            _ => None
+        }
+    }
+
+    fn from_action(action: &Action) -> Option<Event> {
+        match *action {
+            Action::Open{start_frame, run} => Some(Event::PanStart{start_frame, run}),
+            Action::Close(reason) => Some(Event::PanEnd{reason, period: None}),
+            Action::Drop(_) | Action::Append(_) => None
+        }
+    }
+}
+
+pub struct EventLog {
+    out: BufWriter<File>
+}
+
+impl EventLog {
+    pub fn create(path: &Path) -> ::std::io::Result<Self> {
+        Ok(EventLog {out: BufWriter::new(File::create(path)?)})
+    }
+
+    /// continues the log of an interrupted run
+    pub fn append(path: &Path) -> ::std::io::Result<Self> {
+        Ok(EventLog {out: BufWriter::new(OpenOptions::new().create(true).append(true).open(path)?)})
+    }
+
+    pub fn write(&mut self, event: &Event) -> ::std::io::Result<()> {
+        serde_json::to_writer(&mut self.out, event)?;
+        self.out.write_all(b"\n")
+    }
+}
+
+/// Runs the pan detection on the frames of a recorded log again and writes its decisions to `out`.
+/// Uses the recorded profile unless another one is given.
+pub fn replay<W: Write>(path: &Path, profile: Option<Profile>, out: &mut W) -> Result<(), String> {
+    let file = File::open(path).map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+
+    let mut detector : Option<Detector> = None;
+
+    for (nr, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event : Event = serde_json::from_str(&line).map_err(|e| format!("{}:{}: {}", path.display(), nr + 1, e))?;
+
+        match event {
+            Event::Stream{fps, width, height, profile: recorded} => {
+                // a resumed run appends its own header, the interrupted one ends here
+                if let Some(mut previous) = detector.take() {
+                    for e in previous.finish().iter().filter_map(Event::from_action) {
+                        emit(out, &e)?;
+                    }
+                }
+                let profile = profile.unwrap_or(recorded);
+                detector = Some(Detector::new(profile.thresholds(fps, width, height)));
+                emit(out, &Event::Stream{fps, width, height, profile})?;
+            }
+            Event::Frame{idx, ..} => {
+                let detector = detector.as_mut().ok_or(format!("{}:{}: frame before stream header", path.display(), nr + 1))?;
+                let actions = detector.push(event.frame_stats().unwrap());
+                emit(out, &Event::Run{idx, run: detector.last_run()})?;
+                for e in actions.iter().filter_map(Event::from_action) {
+                    emit(out, &e)?;
+                }
+            }
+            // earlier decisions get recomputed
+            _ => {}
+        }
+    }
+
+    if let Some(mut detector) = detector {
+        for e in detector.finish().iter().filter_map(Event::from_action) {
+            emit(out, &e)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn emit<W: Write>(out: &mut W, event: &Event) -> Result<(), String> {
+    serde_json::to_writer(&mut *out, event).map_err(|e| e.to_string())?;
+    out.write_all(b"\n").map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn estimate_roundtrip() {
+        let mut est = Estimate::still(1000);
+        est.x = -3;
+        est.histogram[12] = 7;
+
+        let line = serde_json::to_string(&Event::Frame {
+            idx: 2, pred_idx: Some(1), pts: Some(2002), time: Some(0.083), interval: Some(0.042),
+            frame_type: "P".to_owned(), repeats: 1,
+            luma: Quantiles {min: 0, q10: 20, q25: 40, q50: 80, q75: 120, q90: 200, max: 255, avg: 90, mode: 80},
+            comparisons: vec![Comparison::new(1, est), Comparison::new(3, est.reverse())]
+        }).unwrap();
+
+        let stats = serde_json::from_str::<Event>(&line).unwrap().frame_stats().unwrap();
+        assert_eq!(stats.luma_spread, 180);
+        assert!(stats.estimate.unwrap() == est);
+        assert_eq!(stats.estimate.unwrap().x, -3);
+    }
+}
@@ -0,0 +1,287 @@
+use ffmpeg;
+use ffmpeg::filter;
+use ffmpeg::frame::Video;
+use ffmpeg::util::format::pixel::Pixel;
+use std::collections::VecDeque;
+use std::cmp::max;
+use budget::{Job, Reservation};
+
+// combing makes every other row deviate from its neighbours, which throws off the SAD based
+// motion search and leaves comb artifacts in the composites.
+// field matching (inverse telecine) or deinterlacing is delegated to ffmpeg's filters,
+// we only decide whether they are needed.
+
+/// luma difference between neighbouring rows that counts as combing
+const COMB_THRESHOLD : i32 = 12;
+/// fraction of combed samples above which a frame counts as combed
+const COMBED_FRAME : f32 = 0.005;
+/// frames to inspect before deciding how to handle fields in auto mode
+const SAMPLE_FRAMES : usize = 48;
+/// fraction of combed frames among the samples that enables field processing
+const COMBED_SOURCE : f32 = 0.1;
+
+arg_enum!{
+    #[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+    pub enum FieldMode {
+        Auto, Off, Ivtc, Deint
+    }
+}
+
+impl FieldMode {
+    fn filter_spec(self) -> Option<&'static str> {
+        match self {
+            FieldMode::Ivtc => Some("fieldmatch=combmatch=full,yadif=deint=interlaced,decimate"),
+            FieldMode::Deint => Some("yadif=deint=interlaced"),
+            FieldMode::Auto | FieldMode::Off => None
+        }
+    }
+}
+
+/// fraction of sampled pixels that deviate in the same direction from the rows above and below
+/// while those two agree with each other
+pub fn comb_score(frame: &Video) -> f32 {
+    let bpp = match frame.format() {
+        Pixel::YUV420P | Pixel::YUV444P => 1,
+        Pixel::YUV420P10LE | Pixel::YUV444P10LE => 2,
+        fm @ _ => unimplemented!("pixel format {:?} currently not supported", fm)
+    };
+
+    let data = frame.data(0);
+    let stride = frame.stride(0);
+    let (w, h) = (frame.width() as usize, frame.height() as usize);
+
+    let px = |x: usize, y: usize| -> i32 {
+        let i = y * stride + x * bpp;
+        if bpp == 2 {
+            (data[i] as i32 | (data[i + 1] as i32) << 8) >> 2
+        } else {
+            data[i] as i32
+        }
+    };
+
+    let mut combed = 0;
+    let mut total = 0;
+
+    for y in (1 .. h - 1).step_by(2) {
+        for x in (0 .. w).step_by(4) {
+            let (above, cur, below) = (px(x, y - 1), px(x, y), px(x, y + 1));
+            total += 1;
+            if (cur - above) * (cur - below) > COMB_THRESHOLD * COMB_THRESHOLD && (above - below).abs() < COMB_THRESHOLD {
+                combed += 1;
+            }
+        }
+    }
+
+    combed as f32 / max(total, 1) as f32
+}
+
+/// picks field matching for sources that look like 3:2 pulldown of film and deinterlacing for everything else
+fn choose_mode(combed: usize, sampled: usize, frame_rate: ffmpeg::Rational) -> FieldMode {
+    if (combed as f32 / max(sampled, 1) as f32) < COMBED_SOURCE {
+        return FieldMode::Off;
+    }
+
+    let fps = frame_rate.numerator() as f64 / max(frame_rate.denominator(), 1) as f64;
+    if (fps - 30000.0 / 1001.0).abs() < 0.05 || (fps - 30.0).abs() < 0.05 {
+        FieldMode::Ivtc
+    } else {
+        FieldMode::Deint
+    }
+}
+
+struct FieldFilter {
+    graph: filter::Graph
+}
+
+impl FieldFilter {
+    fn new(spec: &str, format: Pixel, width: u32, height: u32, time_base: ffmpeg::Rational, sar: ffmpeg::Rational) -> Result<Self, ffmpeg::Error> {
+        let mut graph = filter::Graph::new();
+
+        let pix_fmt : ffmpeg::ffi::AVPixelFormat = format.into();
+        let args = format!("video_size={}x{}:pix_fmt={}:time_base={}/{}:pixel_aspect={}/{}",
+                           width, height, pix_fmt as i32,
+                           time_base.numerator(), time_base.denominator(),
+                           max(sar.numerator(), 1), max(sar.denominator(), 1));
+
+        graph.add(&filter::find("buffer").unwrap(), "in", &args)?;
+        graph.add(&filter::find("buffersink").unwrap(), "out", "")?;
+
+        {
+            let mut out = graph.get("out").unwrap();
+            out.set_pixel_format(format);
+        }
+
+        graph.output("in", 0)?.input("out", 0)?.parse(spec)?;
+        graph.validate()?;
+
+        Ok(FieldFilter {graph})
+    }
+
+    fn push(&mut self, frame: &Video) {
+        if let Err(e) = self.graph.get("in").unwrap().source().add(frame) {
+            eprintln!("field filter: {:?}", e);
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Err(e) = self.graph.get("in").unwrap().source().flush() {
+            eprintln!("field filter: {:?}", e);
+        }
+    }
+
+    fn pull(&mut self) -> Option<Video> {
+        let mut filtered = Video::empty();
+        match self.graph.get("out").unwrap().sink().frame(&mut filtered) {
+            Ok(_) => Some(filtered),
+            Err(_) => None
+        }
+    }
+}
+
+/// Turns decoded frames into progressive frames before they enter the motion search
+pub(crate) struct Fields {
+    mode: FieldMode,
+    filter: Option<FieldFilter>,
+    /// frames held back while auto mode inspects the source, accounted to the memory budget
+    samples: Vec<(Video, Reservation)>,
+    combed: usize,
+    format: Pixel,
+    width: u32,
+    height: u32,
+    time_base: ffmpeg::Rational,
+    frame_rate: ffmpeg::Rational,
+    ready: VecDeque<Video>
+}
+
+impl Fields {
+    pub fn new(mode: FieldMode, decoder: &ffmpeg::decoder::Video, time_base: ffmpeg::Rational, frame_rate: ffmpeg::Rational) -> Self {
+        let mut fields = Fields {
+            mode: FieldMode::Off,
+            filter: None,
+            samples: vec![],
+            combed: 0,
+            format: decoder.format(),
+            width: decoder.width(),
+            height: decoder.height(),
+            time_base,
+            frame_rate,
+            ready: VecDeque::new()
+        };
+
+        fields.set_mode(mode, decoder.aspect_ratio());
+        fields
+    }
+
+    pub fn mode(&self) -> FieldMode {
+        self.mode
+    }
+
+    fn set_mode(&mut self, mode: FieldMode, sar: ffmpeg::Rational) {
+        self.mode = mode;
+
+        if let Some(spec) = mode.filter_spec() {
+            match FieldFilter::new(spec, self.format, self.width, self.height, self.time_base, sar) {
+                Ok(filter) => self.filter = Some(filter),
+                Err(e) => {
+                    eprintln!("could not set up {:?} filter, processing fields as is: {}", mode, e);
+                    self.mode = FieldMode::Off;
+                }
+            }
+        }
+    }
+
+    fn decide(&mut self) {
+        let sar = self.samples.first().map(|&(ref f, _)| f.aspect_ratio()).unwrap_or(ffmpeg::Rational(1, 1));
+        let mode = choose_mode(self.combed, self.samples.len(), self.frame_rate);
+        self.set_mode(mode, sar);
+
+        // the memory is accounted again once the frames are sent on
+        for (frame, _memory) in ::std::mem::replace(&mut self.samples, vec![]) {
+            self.process(frame);
+        }
+    }
+
+    fn process(&mut self, frame: Video) {
+        match self.filter.as_mut() {
+            Some(filter) => {
+                filter.push(&frame);
+                while let Some(filtered) = filter.pull() {
+                    self.ready.push_back(filtered);
+                }
+            }
+            None => self.ready.push_back(frame)
+        }
+    }
+
+    /// `job` accounts the frames held back while sampling
+    pub fn push(&mut self, mut frame: Video, job: &Job) {
+        // the field filters rely on timestamps
+        let ts = frame.timestamp();
+        frame.set_pts(ts);
+
+        if self.mode == FieldMode::Auto {
+            if comb_score(&frame) > COMBED_FRAME {
+                self.combed += 1;
+            }
+            let memory = job.reserve_frame(&frame);
+            self.samples.push((frame, memory));
+            if self.samples.len() >= SAMPLE_FRAMES {
+                self.decide();
+            }
+            return;
+        }
+
+        self.process(frame);
+    }
+
+    /// end of stream, releases everything that is still buffered
+    pub fn flush(&mut self) {
+        if self.mode == FieldMode::Auto {
+            self.decide();
+        }
+
+        if let Some(filter) = self.filter.as_mut() {
+            filter.flush();
+            while let Some(filtered) = filter.pull() {
+                self.ready.push_back(filtered);
+            }
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<Video> {
+        self.ready.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ffmpeg::Rational;
+    use ffmpeg::frame::Video;
+    use ffmpeg::util::format::pixel::Pixel;
+    use super::{comb_score, choose_mode, FieldMode, COMBED_FRAME};
+
+    fn luma<F: Fn(usize, usize) -> u8>(f: F) -> Video {
+        let mut frame = Video::new(Pixel::YUV420P, 64, 32);
+        let stride = frame.stride(0);
+        for (i, p) in frame.data_mut(0).iter_mut().enumerate() {
+            *p = f(i % stride, i / stride);
+        }
+        frame
+    }
+
+    #[test]
+    fn combed_frame() {
+        // the fields of a horizontal move, one shifted against the other
+        let frame = luma(|x, y| if (x / 8 + y % 2) % 2 == 0 { 40 } else { 200 });
+        assert!(comb_score(&frame) > COMBED_FRAME);
+        assert_eq!(choose_mode(12, 48, Rational(30000, 1001)), FieldMode::Ivtc);
+        assert_eq!(choose_mode(12, 48, Rational(25, 1)), FieldMode::Deint);
+    }
+
+    #[test]
+    fn progressive_frame() {
+        let frame = luma(|x, y| (x * 2 + y * 3) as u8);
+        assert_eq!(comb_score(&frame), 0.0);
+        assert_eq!(choose_mode(1, 48, Rational(30000, 1001)), FieldMode::Off);
+    }
+}
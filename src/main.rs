@@ -17,48 +17,195 @@ extern crate float_ord;
 extern crate atomic;
 extern crate oxipng;
 extern crate std_semaphore;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate toml;
+extern crate serde_json;
+extern crate flate2;
 
 mod stitchers;
 mod motion;
 mod pipeline;
+mod interlace;
+mod position;
+mod profile;
+mod detect;
+mod events;
+mod manifest;
+mod restitch;
+mod naming;
+mod error;
+mod budget;
+mod clip;
 
 use ffmpeg::codec::threading;
 use std::path::*;
-use clap::{Arg, App};
+use clap::{Arg, App, AppSettings, SubCommand};
 use std::io::BufRead;
 use motion::vectors::MVInfo;
 use pipeline::{PanFinder, Format, MVPrefilter, MVFrame};
+use clip::Clip;
+use interlace::{Fields, FieldMode};
+use position::{Position, Timestamp};
+use profile::Profile;
+use manifest::Manifest;
+use naming::Naming;
+use stitchers::output::{Layout, Encoding};
+use error::Error;
+use budget::{Budget, Job};
 use rayon::prelude::*;
+use std::sync::mpsc::SyncSender;
+use std::sync::{Arc, Mutex};
+
+
+/// numbers decoded frames and hands them to the prefilter
+struct FrameFeed {
+    to_prefilter: SyncSender<MVFrame>,
+    ctx: *mut ffmpeg::ffi::AVFormatContext,
+    stream: *mut ffmpeg::ffi::AVStream,
+    counter: u32,
+    end: u32,
+    /// frames before this timestamp are decoded but discarded
+    start_pts: i64,
+    /// display frame the decoding starts at, for frames without timestamps
+    untimed_start: u32,
+    /// frames numbered below this are decoded but discarded, for streams without timestamps which are not seeked
+    skip_until: u32,
+    half_frame: i64,
+    stream_start: i64,
+    time_base: f64,
+    fps: f64,
+    duration: u32,
+    started: bool,
+    stream_time_base: (i32, i32),
+    last_pts: Option<i64>,
+    /// what the field filter settled on, for the manifest
+    field_mode: Arc<Mutex<FieldMode>>,
+    job: Arc<Job>
+}
 
+impl FrameFeed {
+    /// number frames starting at the display frame `idx`
+    fn start_at(&mut self, idx: u32) {
+        self.counter = idx;
+        self.end = ::std::cmp::max(idx, self.skip_until).saturating_add(self.duration);
+        self.started = true;
+    }
 
+    /// returns false once the frame limit has been reached
+    fn decoded(&mut self, frame: ffmpeg::frame::Video, fields: &mut Fields) -> bool {
+        if let Some(ts) = frame.timestamp() {
+            if ts < self.start_pts - self.half_frame {
+                return true;
+            }
 
+            if !self.started {
+                let idx = ((ts - self.stream_start) as f64 * self.time_base * self.fps).round();
+                self.start_at(if idx > 0.0 { idx as u32 } else { 0 });
+            }
+        } else if !self.started {
+            // keeps the numbering of a resumed or seeking run in line with the earlier sequences
+            let idx = self.untimed_start;
+            self.start_at(idx);
+        }
 
-fn process_video(input: &Path, config: Config) {
-    match ffmpeg::format::input(&input) {
-        Ok(mut ctx) => {
-            let mut vdecoder;
-            let vid_idx;
+        self.started = true;
+        fields.push(frame, &self.job);
+        *self.field_mode.lock().unwrap() = fields.mode();
 
-            /*
+        while let Some(frame) = fields.pop() {
+            if !self.send(frame) {
+                return false;
+            }
+        }
 
-            if start > 0 {
-                unsafe {
-                    use ffmpeg::ffi;
-                    let ctx = ctx.as_mut_ptr();
-                    let res = ffi::avformat_seek_file(ctx, -1, 0, start as i64, start as i64, ffi::AVSEEK_FLAG_FRAME);
-                    if res != 0 {
-                        panic!("seek failed, error code:{}", res);
-                    }
-                }
+        true
+    }
+
+    /// returns false once the frame limit has been reached
+    fn send(&mut self, mut frame: ffmpeg::frame::Video) -> bool {
+        let timestamp = frame.timestamp().map(|pts| Timestamp::new(pts, self.stream_time_base));
+
+        // repeated timestamps are duplicates, e.g. from broken muxers. dropped frames just leave a gap
+        if let (Some(ts), Some(last)) = (timestamp, self.last_pts) {
+            if ts.pts <= last {
+                return true;
+            }
+        }
+        self.last_pts = timestamp.map(|t| t.pts).or(self.last_pts);
+
+        if self.counter >= self.end {
+            return false;
+        }
+
+        if self.counter < self.skip_until {
+            self.counter += 1;
+            return true;
+        }
+
+        let (sar,frame_type) = unsafe {
+            let frame_ptr = frame.as_mut_ptr();
+            use ffmpeg::ffi::*;
+
+            (av_guess_sample_aspect_ratio(self.ctx,
+                                          self.stream,
+                                          frame_ptr),
+             (*frame_ptr).pict_type
+            )
+        };
+
+        // blocks while other videos use up the memory budget
+        let memory = self.job.reserve_frame(&frame);
+        let mv_frame = MVFrame::new(MVInfo::new(), frame, frame_type, self.counter, timestamp, sar.into(), memory);
+        // the other threads only hang up if they failed, their error gets reported once they are joined
+        if self.to_prefilter.send(mv_frame).is_err() {
+            return false;
+        }
+
+        self.counter += 1;
+        true
+    }
+}
 
+fn process_video(input: &Path, config: Config, job: Arc<Job>) -> Result<(), Error> {
+    let mut manifest = Manifest::new(input);
+
+    if !config.force {
+        if let Ok(previous) = Manifest::load(&config.naming.output_dir(input)) {
+            if previous.complete {
+                println!("{}: already processed, skipping. use --force to recompute", input.display());
+                return Ok(());
             }
-            */
+            manifest = previous;
+        }
+    }
+
+    let resume_at = manifest.resume_time();
+    let resume_frame = manifest.resume_frame();
+
+    error::ffmpeg_path(input)?;
+
+    match ffmpeg::format::input(&input) {
+        Ok(mut ctx) => {
+            let mut vdecoder;
+            let vid_idx;
+            let time_base;
+            let frame_rate;
+            let timed;
+            let stream_start;
 
             {
-                let vstream = ctx.streams().best(ffmpeg::media::Type::Video).expect(
-                    "video stream",
-                );
+                let vstream = ctx.streams().best(ffmpeg::media::Type::Video).ok_or("no video stream")?;
                 vid_idx = vstream.index();
+                time_base = vstream.time_base();
+                frame_rate = if vstream.avg_frame_rate().numerator() > 0 {
+                    vstream.avg_frame_rate()
+                } else {
+                    vstream.rate()
+                };
+                timed = vstream.start_time() != ffmpeg::ffi::AV_NOPTS_VALUE;
+                stream_start = if timed { vstream.start_time() } else { 0 };
                 let mut decoder = vstream.codec().decoder();
                 unsafe {
                     let ctx = decoder.as_mut_ptr();
@@ -71,9 +218,13 @@ fn process_video(input: &Path, config: Config) {
                     safe: true,
                 });
 
-                vdecoder = decoder.video().unwrap();
+                vdecoder = decoder.video()?;
             };
 
+            if !MVFrame::supported_format(vdecoder.format()) {
+                return Err(format!("pixel format {:?} currently not supported", vdecoder.format()).into());
+            }
+
             use std::thread;
             use std::sync::mpsc::sync_channel;
 
@@ -81,8 +232,10 @@ fn process_video(input: &Path, config: Config) {
             let (to_pan_finder, finder_rx) = sync_channel(25);
             let (to_image_writer, writer_rx) = sync_channel(3);
 
+            let (subsample, collapse_held, profile) = (config.subsample, config.collapse_held, config.profile);
+
             thread::spawn(move || {
-                let mut filter = MVPrefilter::new(config.subsample);
+                let mut filter = MVPrefilter::new(subsample, collapse_held, profile);
 
                 let mut batch = vec![];
 
@@ -99,70 +252,163 @@ fn process_video(input: &Path, config: Config) {
 
 
                     while let Some(frame) = filter.poll() {
-                        to_pan_finder.send(frame).unwrap();
+                        if to_pan_finder.send(frame).is_err() {
+                            return;
+                        }
                     }
                 }
 
                 for remainder in filter.drain() {
-                    to_pan_finder.send(remainder).unwrap();
+                    if to_pan_finder.send(remainder).is_err() {
+                        return;
+                    }
                 }
             });
 
 
-            let p = PanFinder::output_path_from_input(&input);
-            let p2 = p.clone();
+            let out_dir = config.naming.output_dir(input);
+            let resumed = manifest.sequences.len();
+            let finder_config = config.clone();
+            let finder_input = input.to_owned();
+            let finder_job = job.clone();
+            let optimize = config.optimize;
 
-            thread::spawn(move || {
-                let mut writer = PanFinder::new(p, config);
+            let fps = frame_rate.numerator() as f64 / std::cmp::max(frame_rate.denominator(), 1) as f64;
+            let (width, height) = (vdecoder.width(), vdecoder.height());
+
+            let finder = thread::spawn(move || -> Result<(), Error> {
+                let mut writer = PanFinder::new(&finder_input, finder_config, finder_job, resumed, fps, width, height)?;
 
                 while let Ok(mv_frame) = finder_rx.recv() {
-                    writer.add_frame(mv_frame);
-                    for out in writer.image_batches.drain(..).filter(|out| {
-                        out.expansion_ratio() < config.min_expand
-                    }) {
-                        to_image_writer.send(out).unwrap();
+                    writer.add_frame(mv_frame)?;
+                    for out in writer.finished.drain(..) {
+                        to_image_writer.send(out).map_err(|_| "image writer stopped")?;
                     }
                 }
 
-                for out in writer.close().into_iter().filter(|out| {
-                    out.expansion_ratio() < config.min_expand
-                }) {
-                    to_image_writer.send(out).unwrap();
+                for out in writer.close()? {
+                    to_image_writer.send(out).map_err(|_| "image writer stopped")?;
                 }
+
+                Ok(())
             });
 
+            let field_mode = Arc::new(Mutex::new(config.fields));
+            let writer_field_mode = field_mode.clone();
             let thread = thread::spawn(move || {
 
                 loop {
                     let mut batch = vec![];
                     batch.push(match writer_rx.recv() {
-                        Ok(stitcher) => stitcher,
+                        Ok(out) => out,
                         _ => break
                     });
 
                     batch.extend(writer_rx.try_iter());
 
-                    batch.into_par_iter().for_each(|stitcher| {
-                        stitcher.write_linear_stitch(config.optimize, &p2);
-                    });
+                    let fields = *writer_field_mode.lock().unwrap();
+                    let sequences : Vec<_> = batch.into_par_iter().map(|(mut sequence, stitcher)| {
+                        sequence.fields = Some(fields);
+                        if let Some(stitcher) = stitcher {
+                            match stitcher.write_linear_stitch(optimize, &out_dir) {
+                                Ok(extras) => {
+                                    if !extras.stabilized {
+                                        sequence.stabilized = None;
+                                    }
+                                    if !extras.trajectory {
+                                        sequence.trajectory.clear();
+                                    }
+                                },
+                                Err(e) => {
+                                    eprintln!("{}: writing composite failed: {}", sequence.name, e);
+                                    sequence.composites.clear();
+                                    sequence.stabilized = None;
+                                    sequence.trajectory.clear();
+                                }
+                            }
+                        }
+                        sequence
+                    }).collect();
+
+                    // everything up to here is on disk
+                    for sequence in sequences {
+                        manifest.add(sequence);
+                    }
+                    if let Err(e) = manifest.save(&out_dir) {
+                        eprintln!("{}", e);
+                    }
                 }
-            });
 
-            let mut frame_counter = 0;
+                manifest
+            });
 
+            let tb = time_base.numerator() as f64 / std::cmp::max(time_base.denominator(), 1) as f64;
+            let duration = config.duration.map(|d| d.frames(fps)).unwrap_or(std::u32::MAX);
 
-            let ctxptr = unsafe {
-                ctx.as_mut_ptr()
+            // display frames before this timestamp get decoded but discarded
+            let mut start_secs = config.seek.seconds(fps);
+            // continue with the frame after the last completed sequence
+            let resume_secs = match (resume_at, resume_frame) {
+                (Some(end), _) => Some(end - stream_start as f64 * tb + 1.0 / fps),
+                (None, Some(frame)) => Some((frame + 1) as f64 / fps),
+                (None, None) => None
             };
+            if let Some(resume_secs) = resume_secs {
+                if resume_secs > start_secs {
+                    println!("{}: resuming at {:.3}s", input.display(), resume_secs);
+                    start_secs = resume_secs;
+                }
+            }
+            let start_pts = stream_start + (start_secs / tb).round() as i64;
+            let half_frame = (0.5 / fps / tb) as i64;
 
-            for (stream, packet) in ctx.packets() {
-                if stream.index() != vid_idx {
-                    continue;
+            // where a seek lands can't be told without timestamps, such streams are decoded from the start and counted
+            let start_frame = (start_secs * fps).round() as u32;
+            if start_secs > 0.0 && timed {
+                unsafe {
+                    use ffmpeg::ffi;
+                    let ctx = ctx.as_mut_ptr();
+                    let mut ts = (start_secs * ffi::AV_TIME_BASE as f64) as i64;
+                    if (*ctx).start_time != ffi::AV_NOPTS_VALUE {
+                        ts += (*ctx).start_time;
+                    }
+                    // lands on the closest keyframe at or before the target
+                    let res = ffi::avformat_seek_file(ctx, -1, std::i64::MIN, ts, ts, 0);
+                    if res < 0 {
+                        eprintln!("seek failed ({}), decoding from the start instead", ffmpeg::Error::from(res));
+                    }
                 }
+            }
+
+            let mut fields = Fields::new(config.fields, &vdecoder, time_base, frame_rate);
+
+            let mut feed = unsafe {
+                FrameFeed {
+                    to_prefilter,
+                    ctx: ctx.as_mut_ptr(),
+                    stream: ctx.stream(vid_idx).unwrap().as_ptr() as *mut ffmpeg::ffi::AVStream,
+                    counter: 0,
+                    end: duration,
+                    start_pts,
+                    untimed_start: if timed { start_frame } else { 0 },
+                    skip_until: if timed { 0 } else { start_frame },
+                    half_frame,
+                    stream_start,
+                    time_base: tb,
+                    fps,
+                    duration,
+                    started: false,
+                    stream_time_base: (time_base.numerator(), time_base.denominator()),
+                    last_pts: None,
+                    field_mode,
+                    job
+                }
+            };
+
+            let mut completed = true;
 
-                // TODO: find previous keyframe, seek back, skip forwards while decoding
-                if frame_counter < config.seek {
-                    frame_counter += 1;
+            'packets: for (stream, packet) in ctx.packets() {
+                if stream.index() != vid_idx {
                     continue;
                 }
 
@@ -179,29 +425,13 @@ fn process_video(input: &Path, config: Config) {
 
                         //let frame_idx = frame.display_number();
 
-                        let (sar,frame_type) = unsafe {
-                            let frame_ptr = frame.as_mut_ptr();
-                            use ffmpeg::ffi::*;
-
-                            (av_guess_sample_aspect_ratio(ctxptr,
-                                                          stream.as_ptr() as *mut AVStream,
-                                                          frame_ptr),
-                             (*frame_ptr).pict_type
-                            )
-                        };
-
-
-                        let mv_frame = MVFrame::new(MVInfo::new(), frame, frame_type, frame_counter, sar.into());
-                        to_prefilter.send(mv_frame).unwrap();
-
-                        if frame_counter > config.seek.saturating_add(config.max_frames) {
-                            break;
+                        if !feed.decoded(frame, &mut fields) {
+                            completed = false;
+                            break 'packets;
                         }
-
-                        frame_counter += 1;
                     }
                     Ok(false) => {
-                        // TODO: handle decoder flushing at end of stream
+                        // decoder is still filling its pipeline
                     }
                     Err(e) => {
                         eprintln!("{:?}", e);
@@ -209,48 +439,200 @@ fn process_video(input: &Path, config: Config) {
                 }
             }
 
-            drop(to_prefilter);
+            // the frame-threaded decoder still holds the last frames, empty packets drain it
+            while completed {
+                let mut frame = ffmpeg::frame::Video::empty();
+                match vdecoder.decode(&ffmpeg::packet::Packet::empty(), &mut frame) {
+                    Ok(true) => {
+                        completed = feed.decoded(frame, &mut fields);
+                    }
+                    Ok(false) => break,
+                    Err(e) => {
+                        eprintln!("{:?}", e);
+                        break;
+                    }
+                }
+            }
 
-            thread.join().unwrap();
-        }
-        Err(e) => {
-            eprintln!("{}", e);
+            if completed {
+                fields.flush();
+
+                while let Some(frame) = fields.pop() {
+                    if !feed.send(frame) {
+                        break;
+                    }
+                }
+            }
+
+            if config.log {
+                println!("{}: fields {:?}", input.display(), fields.mode());
+            }
+
+            drop(feed);
+
+            let mut manifest = thread.join().map_err(|_| "image writer panicked")?;
+            let detected = finder.join().map_err(|_| Error::from("pan detection panicked")).and_then(|r| r);
+            manifest.complete = detected.is_ok() && completed && config.seek.seconds(fps) == 0.0;
+
+            // keep what was written so far even if the pan detection failed
+            manifest.save(&config.naming.output_dir(input))?;
+            detected
         }
+        Err(e) => Err(e.into())
     }
 }
 
-#[derive(Copy,Clone)]
+#[derive(Clone)]
 struct Config {
     optimize: bool,
     single_frame_format: Format,
-    seek: u32,
-    max_frames :u32,
+    /// --quality, applies to jpg and webp
+    quality: Option<u8>,
+    /// also write each sequence as a video
+    clip: Option<Clip>,
+    /// also write each composited sequence as a video on the canvas
+    stabilize: Option<Clip>,
+    /// stabilized clips show the composite around the frames
+    backdrop: bool,
+    trajectory: bool,
+    seek: Position,
+    duration: Option<Position>,
     subsample: u8,
     min_expand: f32,
     log: bool,
-    stitch: bool
+    stitch: bool,
+    layers: u8,
+    loops: bool,
+    collapse_held: bool,
+    fields: FieldMode,
+    profile: Profile,
+    force: bool,
+    naming: Naming,
+    /// bytes of frames a stitcher keeps in memory before spilling to disk, None for no limit
+    stitch_memory: Option<usize>,
+    layout: Layout,
+    tile_size: u32,
+    composite_format: Encoding,
+    composite_quality: Option<u8>
+}
+
+/// 1-100, exits on anything else
+fn quality(value: Option<&str>, option: &str) -> Option<u8> {
+    match value.map(|q| q.parse::<u8>()) {
+        None => None,
+        Some(Ok(q)) if q >= 1 && q <= 100 => Some(q),
+        Some(_) => {
+            eprintln!("{} must be a number between 1 and 100", option);
+            std::process::exit(1);
+        }
+    }
 }
 
 fn main() {
     let matches = App::new("animation linear panning detection, scene extraction and stitching for videos")
         .version(crate_version!())
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(SubCommand::with_name("restitch")
+            .about("composites sequences again from the alignment saved in manifest.json, skipping the motion search. \
+                    composite options like --layers or --opt go before the subcommand")
+            .arg(Arg::with_name("video").index(1).required(true).help("the video the sequences were found in"))
+            .arg(Arg::with_name("seq").long("seq").takes_value(true).multiple(true)
+                .help("names of the sequences to restitch, e.g. 000042_00h00m01s750. default all"))
+            .arg(Arg::with_name("manifest").long("manifest").takes_value(true)
+                .help("read the alignment from this file instead of <video>.seq/manifest.json, e.g. an edited copy with corrected offsets")))
         .arg(Arg::with_name("nostitch").long("nostitch").required(false).takes_value(false)
             .help("do not create composite images"))
         .arg(Arg::with_name("pic_format").short("p").long("pictures").required(false).takes_value(true)
-            .possible_values(&["png","jpg"]).help("write individual frames of detected pans to disk"))
+            .possible_values(&["png","png16","jpg","webp","tiff"]).case_insensitive(true)
+            .help("write individual frames of detected pans to disk. png16 keeps the depth of 10 bit sources, webp is lossless by default"))
+        .arg(Arg::with_name("quality").long("quality").takes_value(true)
+            .help("quality 1-100 of lossy single frame formats: jpg and webp, which then is no longer lossless"))
+        .arg(Arg::with_name("clip").long("clip").takes_value(true)
+            .possible_values(&Clip::variants())
+            .case_insensitive(true)
+            .help("write each detected pan as a video clip of exactly its frames. ffv1: lossless mkv, h264: high quality mp4"))
+        .arg(Arg::with_name("stabilized").long("stabilized").takes_value(true)
+            .possible_values(&Clip::variants())
+            .case_insensitive(true)
+            .help("write each composited pan as a video clip with the camera motion removed: every frame is placed \
+                   on the composite canvas at its offset, so the background stays fixed. ffv1: lossless mkv, h264: mp4"))
+        .arg(Arg::with_name("backdrop").long("backdrop").takes_value(false)
+            .help("show the composite around the frames of --stabilized clips instead of leaving it transparent (black in h264)"))
+        .arg(Arg::with_name("trajectory").long("trajectory").takes_value(false)
+            .help("write the camera move of each composited pan as <name>_cam.csv, a JSON keyframe file <name>_cam.json \
+                   and an After Effects script <name>_cam.jsx"))
+        .arg(Arg::with_name("loops").long("loops").required(false).takes_value(false)
+            .help("shorten composites of cycling backgrounds to a single period. compares a few frames per pan against \
+                   its start, which takes a while"))
+        .arg(Arg::with_name("keepheld").long("keepheld").required(false).takes_value(false)
+            .help("do not collapse held (duplicate) frames, e.g. animation on twos, into a single frame"))
+        .arg(Arg::with_name("fields").long("fields").takes_value(true)
+            .default_value("auto")
+            .possible_values(&FieldMode::variants())
+            .case_insensitive(true)
+            .help("handling of telecined or interlaced sources. auto inspects the first frames for combing"))
+        .arg(Arg::with_name("profile").long("profile").takes_value(true)
+            .default_value("anime-24p")
+            .help("pan detection thresholds. the preset anime-24p or the path of a TOML file overriding it"))
+        .arg(Arg::with_name("output_dir").long("output-dir").takes_value(true)
+            .default_value(".")
+            .help("directory to create the per-video output directories in"))
+        .arg(Arg::with_name("dir_name").long("dir-name").takes_value(true)
+            .default_value("{stem}.seq")
+            .help("name of the per-video output directory. {stem} is the file name without extension, \
+                   {path} the input path without extension, which keeps videos with the same name in different folders apart"))
+        .arg(Arg::with_name("name").long("name").takes_value(true)
+            .help("base name of the images of a sequence. placeholders: {stem}, {path}, {frame} start frame, \
+                   {time} start time, {index} number of the sequence within the video. default: {frame}_{time}"))
+        .arg(Arg::with_name("jobs").short("j").long("jobs").takes_value(true)
+            .default_value("1")
+            .help("number of videos to process concurrently. they share the worker threads and the memory budget"))
+        .arg(Arg::with_name("memory").long("memory").takes_value(true)
+            .default_value("4096")
+            .help("MiB of decoded frames queued or held by stitchers across all videos. further videos are only started \
+                   and other than the oldest video only decode more frames while below this [lower = less memory, less parallelism]"))
+        .arg(Arg::with_name("stitch_memory").long("stitch-memory").takes_value(true)
+            .default_value("1024")
+            .help("MiB of frames a single composite may keep in memory. further frames are moved to a temporary file \
+                   in $TMPDIR until compositing. 0 = no limit"))
+        .arg(Arg::with_name("layout").long("layout").takes_value(true)
+            .default_value("whole")
+            .possible_values(&Layout::variants())
+            .case_insensitive(true)
+            .help("whole: one PNG per composite. stream: render and encode the PNG in bands, for canvases too large for memory. \
+                   tiles: a directory of tiles and a JSON index of their positions. dzi: a deep zoom tile pyramid for web viewers"))
+        .arg(Arg::with_name("tile_size").long("tile-size").takes_value(true)
+            .default_value("4096")
+            .help("edge length of tiles and height of the bands of streamed PNGs"))
+        .arg(Arg::with_name("composite_format").long("format").takes_value(true)
+            .default_value("png")
+            .possible_values(&Encoding::variants())
+            .case_insensitive(true)
+            .help("image format of composites and tiles. webp is lossless unless --composite-quality is given, \
+                   avif requires an ffmpeg with libaom and the avif muxer. --layout stream always writes PNG"))
+        .arg(Arg::with_name("composite_quality").long("composite-quality").takes_value(true)
+            .help("quality 1-100 of jpg, webp and avif composites [lower = smaller files]"))
+        .arg(Arg::with_name("force").long("force").takes_value(false)
+            .help("process videos again even if their manifest says they are complete, instead of resuming"))
         .arg(Arg::with_name("opt").long("opt").required(false).takes_value(false)
             .help("optimize composite PNGs for size [slower]"))
         .arg(Arg::with_name("inputs").index(1).multiple(true).required(true)
             .help("videos files to process. specify '-' to read a newline-separated list from stdin.\nExample: find /media/videos -type f -name '*.mkv' | stitch-animation -"))
-        .arg(Arg::with_name("seek_to").short("s").takes_value(true).help("seek to frame number [currently inaccurate, specify a lower number than the desired actual frame]"))
-        .arg(Arg::with_name("N").short("n").takes_value(true).help("process at most N frames, after seeking"))
-        .arg(Arg::with_name("log").long("log").takes_value(false).help("log debug info"))
+        .arg(Arg::with_name("seek_to").short("s").takes_value(true).default_value("0").help("seek to frame number or timestamp [[hh:]mm:]ss[.ms]"))
+        .arg(Arg::with_name("N").short("n").takes_value(true).help("process at most N frames or a duration [[hh:]mm:]ss[.ms], after seeking"))
+        .arg(Arg::with_name("log").long("log").takes_value(false).help("write per-frame statistics and pan decisions to <video>.seq/frames.jsonl"))
+        .arg(Arg::with_name("replay").long("replay").takes_value(false)
+            .help("inputs are frames.jsonl files written by --log. runs the pan detection on them again, with --profile if given, and prints the decisions"))
         .arg(Arg::with_name("min").long("min").takes_value(true)
             .default_value("20")
             .help("composites must be at least min% larger than the video frame size [higher = faster, may miss small pans]"))
         .arg(Arg::with_name("S").long("sub").takes_value(true)
             .possible_values(&["1","2","4", "8"])
             .help("subsample motion search by a factor of S. default 2 @ >= 720p, 4 @ >= 1080p, 1 otherwise. [higher = faster, less accurate]"))
+        .arg(Arg::with_name("layers").long("layers").takes_value(true)
+            .default_value("1")
+            .possible_values(&["1","2","3","4"])
+            .help("separate up to N layers moving at different speeds (parallax) and create one composite per layer [slower]"))
         .get_matches();
 
 
@@ -268,23 +650,155 @@ fn main() {
         stitch: !matches.is_present("nostitch"),
         log: matches.is_present("log"),
         single_frame_format: value_t!(matches, "pic_format", Format).unwrap_or(Format::NULL),
-        seek: value_t!(matches, "seek_to", u32).unwrap_or(0),
+        quality: quality(matches.value_of("quality"), "--quality"),
+        clip: value_t!(matches, "clip", Clip).ok(),
+        stabilize: value_t!(matches, "stabilized", Clip).ok(),
+        backdrop: matches.is_present("backdrop"),
+        trajectory: matches.is_present("trajectory"),
+        seek: value_t!(matches, "seek_to", Position).unwrap_or_else(|e| e.exit()),
         subsample: value_t!(matches, "S", u8).unwrap_or(0),
         min_expand: (value_t!(matches, "min", u16).unwrap() as f32 / 100.0) + 1.0,
-        max_frames: value_t!(matches, "N", u32).unwrap_or(std::u32::MAX),
+        duration: if matches.is_present("N") { Some(value_t!(matches, "N", Position).unwrap_or_else(|e| e.exit())) } else { None },
         optimize: matches.is_present("opt"),
+        layers: value_t!(matches, "layers", u8).unwrap(),
+        loops: matches.is_present("loops"),
+        collapse_held: !matches.is_present("keepheld"),
+        fields: value_t!(matches, "fields", FieldMode).unwrap(),
+        force: matches.is_present("force"),
+        layout: value_t!(matches, "layout", Layout).unwrap_or_else(|e| e.exit()),
+        tile_size: value_t!(matches, "tile_size", u32).unwrap_or_else(|e| e.exit()),
+        composite_format: value_t!(matches, "composite_format", Encoding).unwrap_or_else(|e| e.exit()),
+        composite_quality: quality(matches.value_of("composite_quality"), "--composite-quality"),
+        stitch_memory: match value_t!(matches, "stitch_memory", usize).unwrap_or_else(|e| e.exit()) {
+            0 => None,
+            mib => Some(mib * 1024 * 1024)
+        },
+        naming: match Naming::new(Path::new(matches.value_of_os("output_dir").unwrap()),
+                                  matches.value_of("dir_name").unwrap(), matches.value_of("name")) {
+            Ok(n) => n,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        profile: match Profile::load(matches.value_of("profile").unwrap()) {
+            Ok(p) => p,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        },
     };
 
-    for p in matches.values_of_os("inputs").unwrap().map(Path::new) {
-        if p == Path::new("-") {
-            let stdin = std::io::stdin();
-            let reader = stdin.lock();
-            for line in reader.lines() {
-                process_video(Path::new(&line.unwrap()), config);
+    if config.single_frame_format != Format::NULL && ffmpeg::encoder::find_by_name(config.single_frame_format.codec()).is_none() {
+        eprintln!("the linked ffmpeg has no {} encoder", config.single_frame_format.codec());
+        std::process::exit(1);
+    }
+
+    for clip in config.clip.iter().chain(config.stabilize.iter()) {
+        if ffmpeg::encoder::find_by_name(clip.codec()).is_none() {
+            eprintln!("the linked ffmpeg has no {} encoder", clip.codec());
+            std::process::exit(1);
+        }
+    }
+
+    if config.stitch {
+        if let Err(e) = config.composite_format.check_support() {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+
+    let budget = Budget::new(value_t!(matches, "memory", usize).unwrap_or_else(|e| e.exit()) * 1024 * 1024);
+
+    if let Some(sub) = matches.subcommand_matches("restitch") {
+        let video = Path::new(sub.value_of_os("video").unwrap());
+        let manifest = match sub.value_of_os("manifest") {
+            Some(path) => Manifest::load_file(Path::new(path)),
+            None => Manifest::load(&config.naming.output_dir(video))
+        };
+        let names : Vec<String> = sub.values_of("seq").map(|v| v.map(|s| s.to_owned()).collect()).unwrap_or(vec![]);
+
+        if let Err(e) = manifest.map_err(Error::from).and_then(|m| restitch::restitch(video, &m, &names, config, &Budget::admit(&budget))) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if matches.is_present("replay") {
+        let profile = if matches.occurrences_of("profile") > 0 { Some(config.profile) } else { None };
+        let stdout = std::io::stdout();
+        let mut out = stdout.lock();
+        for p in matches.values_of_os("inputs").unwrap().map(Path::new) {
+            if let Err(e) = events::replay(p, profile, &mut out) {
+                eprintln!("{}", e);
+            }
+        }
+        return;
+    }
+
+    let jobs = std::cmp::max(value_t!(matches, "jobs", usize).unwrap_or_else(|e| e.exit()), 1);
+
+    // workers take the next input once there is room in the budget. all of them share the rayon pool
+    let (to_workers, queue) = std::sync::mpsc::sync_channel::<PathBuf>(0);
+    let queue = Arc::new(Mutex::new(queue));
+
+    let workers : Vec<_> = (0..jobs).map(|_| {
+        let queue = queue.clone();
+        let budget = budget.clone();
+        let config = config.clone();
+
+        std::thread::spawn(move || {
+            loop {
+                let input = match queue.lock().unwrap().recv() {
+                    Ok(input) => input,
+                    _ => break
+                };
+                let job = Arc::new(Budget::admit(&budget));
+                // a bug hit by one video must not take the rest of the batch with it
+                let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| process_video(&input, config.clone(), job)));
+                match result {
+                    Ok(Ok(())) => {},
+                    Ok(Err(e)) => eprintln!("{}: {}", input.display(), e),
+                    Err(_) => eprintln!("{}: processing panicked", input.display())
+                }
+            }
+        })
+    }).collect();
+
+    {
+        // only fails once every worker is gone, the inputs still get named
+        let send = |input: PathBuf| {
+            if let Err(e) = to_workers.send(input) {
+                eprintln!("{}: not processed, no worker left", e.0.display());
             }
-            continue;
+        };
+
+        for p in matches.values_of_os("inputs").unwrap().map(Path::new) {
+            if p == Path::new("-") {
+                let stdin = std::io::stdin();
+                let reader = stdin.lock();
+                for line in reader.lines() {
+                    match line {
+                        Ok(line) => send(PathBuf::from(line)),
+                        Err(e) => {
+                            eprintln!("reading the input list failed: {}", e);
+                            break;
+                        }
+                    }
+                }
+                continue;
+            }
+            send(p.to_owned());
+        }
+    }
+
+    drop(to_workers);
+    for worker in workers {
+        if worker.join().is_err() {
+            eprintln!("a worker stopped unexpectedly");
         }
-        process_video(p, config);
     }
 
     let counts = motion::search::COUNTS.load(atomic::Ordering::Relaxed);
@@ -0,0 +1,114 @@
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use serde_json;
+use motion::search::Estimate;
+use detect::PanEnd;
+use interlace::FieldMode;
+
+/// Everything written to a `.seq` directory, saved as `manifest.json` next to the images.
+/// It is rewritten whenever sequences were completed, so an interrupted run can resume after the last one.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub input: PathBuf,
+    /// the whole video was processed
+    #[serde(default)]
+    pub complete: bool,
+    pub sequences: Vec<Sequence>
+}
+
+/// A detected pan. File names are relative to the `.seq` directory
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Sequence {
+    pub name: String,
+    pub start_frame: u32,
+    pub end_frame: u32,
+    /// seconds
+    pub start_time: Option<f64>,
+    pub end_time: Option<f64>,
+    pub end_reason: PanEnd,
+    pub sar: (i32, i32),
+    /// size of the background canvas before aspect correction, None if nothing was stitched
+    pub canvas: Option<(u32, u32)>,
+    pub expansion_ratio: Option<f32>,
+    /// shift after which a cycling background repeats
+    pub period: Option<(isize, isize)>,
+    /// frames in stitching order, the offsets are the positions on the canvas
+    pub frames: Vec<Placement>,
+    pub composites: Vec<String>,
+    pub frame_images: Vec<String>,
+    /// the sequence as a video file
+    #[serde(default)]
+    pub clip: Option<String>,
+    /// the sequence placed on the composite canvas
+    #[serde(default)]
+    pub stabilized: Option<String>,
+    /// the camera move as CSV, JSON and After Effects script
+    #[serde(default)]
+    pub trajectory: Vec<String>,
+    /// how fields were handled, the pts of the frames depend on it
+    #[serde(default)]
+    pub fields: Option<FieldMode>
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Placement {
+    pub idx: u32,
+    pub pts: Option<i64>,
+    pub offset_x: isize,
+    pub offset_y: isize,
+    /// motion relative to the frame it was aligned to
+    pub estimate: Estimate
+}
+
+impl Manifest {
+    pub fn new(input: &Path) -> Self {
+        Manifest {input: input.to_owned(), complete: false, sequences: vec![]}
+    }
+
+    /// seconds after the end of the last completed sequence
+    pub fn resume_time(&self) -> Option<f64> {
+        self.sequences.iter().filter_map(|s| s.end_time).fold(None, |max, t| {
+            Some(max.map(|m: f64| m.max(t)).unwrap_or(t))
+        })
+    }
+
+    /// last frame of the completed sequences, for videos without timestamps
+    pub fn resume_frame(&self) -> Option<u32> {
+        self.sequences.iter().map(|s| s.end_frame).max()
+    }
+
+    /// replaces an entry for the same pan, e.g. from an earlier run that got interrupted
+    pub fn add(&mut self, sequence: Sequence) {
+        self.sequences.retain(|s| s.start_frame != sequence.start_frame);
+        self.sequences.push(sequence);
+    }
+
+    pub fn path(dir: &Path) -> PathBuf {
+        dir.join("manifest.json")
+    }
+
+    pub fn load(dir: &Path) -> Result<Manifest, String> {
+        Manifest::load_file(&Manifest::path(dir))
+    }
+
+    pub fn load_file(path: &Path) -> Result<Manifest, String> {
+        let file = File::open(path).map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+        serde_json::from_reader(BufReader::new(file)).map_err(|e| format!("invalid manifest {}: {}", path.display(), e))
+    }
+
+    /// written next to the old one first, an interrupted save leaves the previous state to resume from
+    pub fn save(&self, dir: &Path) -> Result<(), String> {
+        use std::io::Write;
+
+        let path = Manifest::path(dir);
+        let tmp = dir.join("manifest.json.tmp");
+        {
+            let mut out = BufWriter::new(File::create(&tmp).map_err(|e| format!("could not write {}: {}", tmp.display(), e))?);
+            serde_json::to_writer_pretty(&mut out, self).map_err(|e| e.to_string())?;
+            out.flush().map_err(|e| format!("could not write {}: {}", tmp.display(), e))?;
+            out.get_ref().sync_all().map_err(|e| format!("could not write {}: {}", tmp.display(), e))?;
+        }
+        fs::rename(&tmp, &path).map_err(|e| format!("could not replace {}: {}", path.display(), e))
+    }
+}
@@ -0,0 +1,373 @@
+use ffmpeg::frame::Video;
+use ffmpeg::util::format::pixel::Pixel;
+use motion::search::Estimate;
+use motion::vectors::ToMotionVectors;
+use float_ord::FloatOrd;
+use std::cmp::{min, max};
+use std::collections::HashMap;
+use error::Error;
+
+// Parallax separation
+//
+// the global search only yields the translation of whatever covers most of the frame.
+// here the blocks that don't follow it are collected and searched for their own dominant motion.
+// each motion that explains a large enough group of blocks becomes a layer.
+
+/// edge length of the square blocks frames are segmented into
+pub const BLOCK_SIZE : usize = 16;
+const BLOCK_PIXELS : u32 = (BLOCK_SIZE * BLOCK_SIZE) as u32;
+
+/// mean absolute luma difference per pixel above which a block does not follow a motion
+const RESIDUAL_ERROR : u32 = 6;
+/// blocks with less luma contrast match nearly any offset, leave them to the background
+const MIN_BLOCK_CONTRAST : u8 = 12;
+/// fraction of all blocks that have to move coherently to form a separate layer
+const MIN_LAYER_FRACTION : f32 = 0.03;
+
+#[derive(Clone)]
+pub struct BlockMask {
+    cols: usize,
+    rows: usize,
+    bits: Vec<bool>
+}
+
+impl BlockMask {
+    pub fn new(cols: usize, rows: usize) -> Self {
+        BlockMask {cols, rows, bits: vec![false; cols * rows]}
+    }
+
+    pub fn set(&mut self, col: usize, row: usize) {
+        self.bits[row * self.cols + col] = true;
+    }
+
+    pub fn get(&self, col: usize, row: usize) -> bool {
+        col < self.cols && row < self.rows && self.bits[row * self.cols + col]
+    }
+
+    /// whether the pixel at `x`,`y` of the frame lies in a block of this mask
+    pub fn contains_px(&self, x: usize, y: usize) -> bool {
+        self.get(x / BLOCK_SIZE, y / BLOCK_SIZE)
+    }
+
+    pub fn count(&self) -> usize {
+        self.bits.iter().filter(|b| **b).count()
+    }
+
+    /// center of the blocks in pixels, None if empty
+    pub fn centroid(&self) -> Option<(f32, f32)> {
+        let (mut sx, mut sy, mut n) = (0.0, 0.0, 0);
+        for row in 0..self.rows {
+            for col in 0..self.cols {
+                if self.bits[row * self.cols + col] {
+                    sx += (col as f32 + 0.5) * BLOCK_SIZE as f32;
+                    sy += (row as f32 + 0.5) * BLOCK_SIZE as f32;
+                    n += 1;
+                }
+            }
+        }
+        if n == 0 { None } else { Some((sx / n as f32, sy / n as f32)) }
+    }
+}
+
+#[derive(Clone)]
+pub struct Layer {
+    pub estimate: Estimate,
+    pub mask: BlockMask
+}
+
+pub(crate) struct Luma<'a> {
+    data: &'a [u8],
+    stride: usize,
+    width: usize,
+    height: usize,
+    bpp: usize
+}
+
+impl<'a> Luma<'a> {
+    pub fn new(data: &'a [u8], stride: usize, width: usize, height: usize, bpp: usize) -> Self {
+        Luma {data, stride, width, height, bpp}
+    }
+
+    pub fn from_video(frame: &'a Video) -> Result<Self, Error> {
+        let bpp = match frame.format() {
+            Pixel::YUV420P | Pixel::YUV444P => 1,
+            Pixel::YUV420P10LE | Pixel::YUV444P10LE => 2,
+            fm @ _ => return Err(format!("pixel format {:?} currently not supported", fm).into())
+        };
+
+        Ok(Luma::new(frame.data(0), frame.stride(0), frame.width() as usize, frame.height() as usize, bpp))
+    }
+
+    /// luma value scaled to 8 bits
+    #[inline]
+    fn px(&self, x: usize, y: usize) -> u8 {
+        let idx = y * self.stride + x * self.bpp;
+        if self.bpp == 2 {
+            ((self.data[idx] as u16 | (self.data[idx + 1] as u16) << 8) >> 2) as u8
+        } else {
+            self.data[idx]
+        }
+    }
+
+    fn cols(&self) -> usize {
+        self.width / BLOCK_SIZE
+    }
+
+    fn rows(&self) -> usize {
+        self.height / BLOCK_SIZE
+    }
+}
+
+/// SAD between a block of `current` and the block displaced by `x`,`y` in `predecessor`.
+/// same sign convention as `search::search`. None if the displaced block leaves the predecessor.
+fn block_sad(current: &Luma, predecessor: &Luma, col: usize, row: usize, x: isize, y: isize) -> Option<u32> {
+    let bx = (col * BLOCK_SIZE) as isize + x;
+    let by = (row * BLOCK_SIZE) as isize + y;
+
+    if bx < 0 || by < 0 || bx as usize + BLOCK_SIZE > predecessor.width || by as usize + BLOCK_SIZE > predecessor.height {
+        return None;
+    }
+
+    let (bx, by) = (bx as usize, by as usize);
+    let mut sum = 0;
+
+    for dy in 0..BLOCK_SIZE {
+        for dx in 0..BLOCK_SIZE {
+            let a = current.px(col * BLOCK_SIZE + dx, row * BLOCK_SIZE + dy) as i32;
+            let b = predecessor.px(bx + dx, by + dy) as i32;
+            sum += (a - b).abs() as u32;
+        }
+    }
+
+    Some(sum)
+}
+
+fn block_contrast(frame: &Luma, col: usize, row: usize) -> u8 {
+    let mut lo = 255;
+    let mut hi = 0;
+
+    for y in row * BLOCK_SIZE .. (row + 1) * BLOCK_SIZE {
+        for x in col * BLOCK_SIZE .. (col + 1) * BLOCK_SIZE {
+            let v = frame.px(x, y);
+            lo = min(lo, v);
+            hi = max(hi, v);
+        }
+    }
+
+    hi - lo
+}
+
+/// mean error per pixel of `blocks` at the given offset. None if most blocks leave the predecessor
+fn mean_error(current: &Luma, predecessor: &Luma, blocks: &[(usize, usize)], x: isize, y: isize) -> Option<f32> {
+    let mut sum = 0u64;
+    let mut count = 0;
+
+    for &(col, row) in blocks {
+        if let Some(sad) = block_sad(current, predecessor, col, row, x, y) {
+            sum += sad as u64;
+            count += 1;
+        }
+    }
+
+    if count == 0 || count * 2 < blocks.len() {
+        return None;
+    }
+
+    Some(sum as f32 / (count * BLOCK_PIXELS as usize) as f32)
+}
+
+/// exponential cross search like `search::search`, restricted to the given blocks
+fn refine(current: &Luma, predecessor: &Luma, blocks: &[(usize, usize)], start: (isize, isize)) -> ((isize, isize), f32) {
+    let mut best = start;
+    let mut best_err = mean_error(current, predecessor, blocks, start.0, start.1).unwrap_or(::std::f32::MAX);
+
+    loop {
+        let (x, y) = best;
+        let mut improved = false;
+
+        for i in 0..8 {
+            let step = 1 << i;
+            for &(cx, cy) in &[(x, y + step), (x, y - step), (x + step, y), (x - step, y)] {
+                if let Some(err) = mean_error(current, predecessor, blocks, cx, cy) {
+                    if err < best_err {
+                        best = (cx, cy);
+                        best_err = err;
+                        improved = true;
+                    }
+                }
+            }
+        }
+
+        if !improved {
+            break;
+        }
+    }
+
+    (best, best_err)
+}
+
+/// most common codec motion vectors pointing into the past among the blocks in `mask`
+fn vector_hints(current: &Video, mask: &BlockMask) -> Vec<(isize, isize)> {
+    let mut bins = HashMap::new();
+
+    if let Some(vecs) = current.motion_vecs() {
+        for v in vecs.iter().filter(|v| v.source < 0 && v.dst_x >= 0 && v.dst_y >= 0) {
+            if !mask.contains_px(v.dst_x as usize, v.dst_y as usize) {
+                continue;
+            }
+            let xy = (v.motion_x as isize / v.motion_scale as isize, v.motion_y as isize / v.motion_scale as isize);
+            *bins.entry(xy).or_insert(0) += v.w as u32 * v.h as u32;
+        }
+    }
+
+    let mut sorted = bins.into_iter().collect::<Vec<_>>();
+    sorted.sort_by(|a, b| b.1.cmp(&a.1));
+    sorted.into_iter().take(4).map(|(k, _)| k).collect()
+}
+
+fn layer_from_blocks(current: &Luma, predecessor: &Luma, blocks: &[(usize, usize)], offset: (isize, isize)) -> Layer {
+    let mut mask = BlockMask::new(current.cols(), current.rows());
+    let mut histogram = [0u16; 256];
+    let mut error_sum = 0;
+    let mut error_area = 0;
+
+    for &(col, row) in blocks {
+        mask.set(col, row);
+        let sad = block_sad(current, predecessor, col, row, offset.0, offset.1).unwrap_or(0);
+        error_sum += sad as u64;
+        error_area += min(sad, BLOCK_PIXELS) as u64;
+        let bin = min(sad / BLOCK_PIXELS, 255) as usize;
+        histogram[bin] = histogram[bin].saturating_add(1);
+    }
+
+    let estimate = Estimate {
+        x: offset.0,
+        y: offset.1,
+        area: blocks.len() as u32 * BLOCK_PIXELS,
+        error_sum,
+        error_area,
+        histogram
+    };
+
+    Layer {estimate, mask}
+}
+
+/// Splits `current` into at most `max_layers` layers moving independently of `primary`,
+/// the global motion estimate of `current` relative to `predecessor`.
+/// Blocks following the primary motion belong to none of the returned layers. Sorted by size.
+pub fn segment(current: &Video, predecessor: &Video, primary: &Estimate, max_layers: usize) -> Result<Vec<Layer>, Error> {
+    let cur = Luma::from_video(current)?;
+    let pred = Luma::from_video(predecessor)?;
+
+    Ok(segment_luma(&cur, &pred, (primary.x, primary.y), max_layers, |mask| vector_hints(current, mask)))
+}
+
+pub(crate) fn segment_luma<F>(cur: &Luma, pred: &Luma, primary: (isize, isize), max_layers: usize, mut hints: F) -> Vec<Layer>
+    where F: FnMut(&BlockMask) -> Vec<(isize, isize)>
+{
+    let (cols, rows) = (cur.cols(), cur.rows());
+    let min_blocks = max(1, ((cols * rows) as f32 * MIN_LAYER_FRACTION) as usize);
+
+    // textured blocks not explained by any of the motions found so far
+    let mut residual = vec![];
+    for row in 0..rows {
+        for col in 0..cols {
+            if block_contrast(cur, col, row) < MIN_BLOCK_CONTRAST {
+                continue;
+            }
+            match block_sad(cur, pred, col, row, primary.0, primary.1) {
+                Some(sad) if sad > RESIDUAL_ERROR * BLOCK_PIXELS => residual.push((col, row)),
+                _ => {}
+            }
+        }
+    }
+
+    let mut layers : Vec<Layer> = vec![];
+
+    while layers.len() < max_layers && residual.len() >= min_blocks {
+        let mut mask = BlockMask::new(cols, rows);
+        for &(col, row) in &residual {
+            mask.set(col, row);
+        }
+
+        let mut starts = hints(&mask);
+        starts.push(primary);
+
+        let best = starts.into_iter()
+            .map(|start| refine(cur, pred, &residual, start))
+            .min_by_key(|&(_, err)| FloatOrd(err));
+
+        let offset = match best {
+            Some((offset, _)) => offset,
+            None => break
+        };
+
+        if offset == primary || layers.iter().any(|l| (l.estimate.x, l.estimate.y) == offset) {
+            break;
+        }
+
+        let (claimed, rest) : (Vec<_>, Vec<_>) = residual.iter().cloned().partition(|&(col, row)| {
+            match block_sad(cur, pred, col, row, offset.0, offset.1) {
+                Some(sad) => sad <= RESIDUAL_ERROR * BLOCK_PIXELS,
+                None => false
+            }
+        });
+
+        if claimed.len() < min_blocks {
+            break;
+        }
+
+        layers.push(layer_from_blocks(cur, pred, &claimed, offset));
+        residual = rest;
+    }
+
+    layers.sort_by(|a, b| b.mask.count().cmp(&a.mask.count()));
+    layers
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn texture(seed: u32, x: usize, y: usize) -> u8 {
+        let mut h = seed ^ (x as u32).wrapping_mul(0x9E37_79B1) ^ (y as u32).wrapping_mul(0x85EB_CA77);
+        h ^= h >> 15;
+        h = h.wrapping_mul(0x2C1B_3C6D);
+        h ^= h >> 12;
+        (h & 0xff) as u8
+    }
+
+    #[test]
+    fn foreground_layer() {
+        let (w, h) = (128, 96);
+        let mut pred = vec![0u8; w * h];
+        let mut cur = vec![0u8; w * h];
+
+        // background pans by 4px, a foreground square moves 16px the other way
+        for y in 0..h {
+            for x in 0..w {
+                pred[y * w + x] = if x >= 32 && x < 80 && y >= 32 && y < 64 {
+                    texture(2, x - 32, y - 32)
+                } else {
+                    texture(1, x, y)
+                };
+                cur[y * w + x] = if x >= 48 && x < 96 && y >= 32 && y < 64 {
+                    texture(2, x - 48, y - 32)
+                } else {
+                    texture(1, x + 4, y)
+                };
+            }
+        }
+
+        let cur = Luma::new(&cur, w, w, h, 1);
+        let pred = Luma::new(&pred, w, w, h, 1);
+
+        let layers = segment_luma(&cur, &pred, (4, 0), 2, |_| vec![(-16, 0)]);
+
+        assert_eq!(layers.len(), 1);
+        assert_eq!((layers[0].estimate.x, layers[0].estimate.y), (-16, 0));
+        assert_eq!(layers[0].mask.count(), 6);
+        assert!(layers[0].mask.contains_px(50, 40));
+        assert!(!layers[0].mask.contains_px(10, 10));
+    }
+}
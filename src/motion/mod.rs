@@ -1,2 +1,3 @@
 pub mod vectors;
 pub mod search;
+pub mod layers;
@@ -10,16 +10,37 @@ use simd::x86::sse2::Sse2U8x16;
 use simd::x86::ssse3::Ssse3U8x16;
 use std::cmp::{min, max};
 
-#[derive(Copy)]
+#[derive(Copy, Serialize, Deserialize)]
 pub struct Estimate {
     pub x: isize,
     pub y: isize,
     pub area: u32,
     pub error_sum: u64,
     pub error_area: u64,
+    #[serde(with = "histogram")]
     pub histogram: [u16 ; 256]
 }
 
+// serde only implements fixed size arrays up to 32 elements
+mod histogram {
+    use serde::{Serializer, Deserializer, Deserialize};
+    use serde::de::Error;
+
+    pub fn serialize<S: Serializer>(histogram: &[u16 ; 256], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(histogram.iter())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u16 ; 256], D::Error> {
+        let bins : Vec<u16> = Deserialize::deserialize(deserializer)?;
+        if bins.len() != 256 {
+            return Err(D::Error::invalid_length(bins.len(), &"256 histogram bins"));
+        }
+        let mut histogram = [0 ; 256];
+        histogram.copy_from_slice(&bins);
+        Ok(histogram)
+    }
+}
+
 impl PartialEq for Estimate {
 
     fn eq(&self, other: &Estimate) -> bool {
@@ -118,18 +139,23 @@ enum Mode {
 
 use self::Mode::*;
 
+/// log2 of the `--sub` factor, which is what `error_sum` takes. 0 picks one based on the resolution
+pub fn subsample_level(subsample: u8, height: u32) -> u8 {
+    match subsample {
+        1 | 2 | 4 | 8 => subsample.trailing_zeros() as u8,
+        _ if height >= 1080 => 2,
+        _ if height >= 720 => 1,
+        _ => 0
+    }
+}
+
 pub fn search(current: &Video, predecessor: &Video, hint: Option<(isize, isize)>, subsample: u8) -> Estimate {
     use rayon::prelude::*;
 
     let w = current.width() as isize;
     let h = current.height() as isize;
 
-    let subsample = match subsample {
-        1 | 2 | 4 | 8 => subsample.trailing_zeros(),
-        _ if h >= 1080 => 2,
-        _ if h >= 720 => 1,
-        _ => 0
-    };
+    let subsample = subsample_level(subsample, current.height());
 
     let (x,y) = if let Some(hint) = hint {
         if hint.0.abs() >= w / 2 || hint.1.abs() >= h / 2 {
@@ -202,7 +228,7 @@ pub fn search(current: &Video, predecessor: &Video, hint: Option<(isize, isize)>
         }).collect();
 
         let found = tuples.par_iter().map(|&(x,y)| {
-            error_sum(&predecessor, &current, x,y, subsample as u8)
+            error_sum(&predecessor, &current, x,y, subsample)
         }).min_by_key({|est| FloatOrd(est.error_fraction())}).unwrap_or(best_match);
 
         //print!("{:?} ", found);
@@ -0,0 +1,126 @@
+use std::path::{Component, Path, PathBuf};
+use position::{Timestamp, sequence_name};
+
+// placeholders available in both templates
+const VIDEO_VARS : [&'static str; 2] = ["stem", "path"];
+// placeholders only known once a sequence has been found
+const SEQUENCE_VARS : [&'static str; 3] = ["frame", "time", "index"];
+
+/// Where outputs go and what they are called
+#[derive(Clone, Debug)]
+pub struct Naming {
+    output_dir: PathBuf,
+    dir_template: String,
+    /// None keeps the default of frame number and time
+    name_template: Option<String>
+}
+
+impl Naming {
+    pub fn new(output_dir: &Path, dir_template: &str, name_template: Option<&str>) -> Result<Naming, String> {
+        check(dir_template, &VIDEO_VARS[..])?;
+        if let Some(t) = name_template {
+            let vars : Vec<_> = VIDEO_VARS.iter().chain(SEQUENCE_VARS.iter()).cloned().collect();
+            check(t, &vars)?;
+            // sequences would overwrite each other otherwise
+            if !SEQUENCE_VARS.iter().any(|v| t.contains(&format!("{{{}}}", v))) {
+                return Err(format!("'{}' names every sequence the same, it needs one of {{frame}} {{time}} {{index}}", t));
+            }
+        }
+
+        Ok(Naming {
+            output_dir: output_dir.to_owned(),
+            dir_template: dir_template.to_owned(),
+            name_template: name_template.map(|t| t.to_owned())
+        })
+    }
+
+    /// directory receiving all outputs of a video
+    pub fn output_dir(&self, input: &Path) -> PathBuf {
+        self.output_dir.join(expand(&self.dir_template, &video_vars(input)))
+    }
+
+    /// base name of the composites, single frames and logs of a sequence. `index` counts from 1
+    pub fn sequence(&self, input: &Path, index: usize, frame: u32, start: Option<Timestamp>) -> String {
+        let template = match self.name_template {
+            Some(ref t) => t,
+            None => return sequence_name(frame, start)
+        };
+
+        let mut vars = video_vars(input);
+        // slashes would create directories
+        for v in vars.iter_mut() {
+            v.1 = v.1.replace('/', "_");
+        }
+        vars.push(("frame", format!("{:06}", frame)));
+        vars.push(("time", start.map(|t| t.file_name()).unwrap_or(String::new())));
+        vars.push(("index", format!("{:03}", index)));
+
+        expand(template, &vars)
+    }
+}
+
+impl Default for Naming {
+    fn default() -> Self {
+        Naming::new(Path::new("."), "{stem}.seq", None).unwrap()
+    }
+}
+
+fn video_vars(input: &Path) -> Vec<(&'static str, String)> {
+    let stem = input.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or(String::new());
+
+    // the input path without extension and without anything that would leave the output directory
+    let mut path = PathBuf::new();
+    for c in input.with_extension("").components() {
+        if let Component::Normal(part) = c {
+            path.push(part);
+        }
+    }
+
+    vec![("stem", stem), ("path", path.to_string_lossy().into_owned())]
+}
+
+fn expand(template: &str, vars: &[(&'static str, String)]) -> String {
+    let mut out = template.to_owned();
+    for &(name, ref value) in vars {
+        out = out.replace(&format!("{{{}}}", name), value);
+    }
+    out
+}
+
+fn check(template: &str, known: &[&'static str]) -> Result<(), String> {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..].find('}').ok_or(format!("unclosed placeholder in '{}'", template))?;
+        let name = &rest[start + 1 .. start + end];
+        if !known.iter().any(|k| *k == name) {
+            return Err(format!("unknown placeholder {{{}}} in '{}', expected one of {}", name, template,
+                               known.iter().map(|k| format!("{{{}}}", k)).collect::<Vec<_>>().join(" ")));
+        }
+        rest = &rest[start + end + 1..];
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::Naming;
+    use position::Timestamp;
+    use std::path::Path;
+
+    #[test]
+    fn templates() {
+        let input = Path::new("/media/show/s01/ep01.mkv");
+
+        let default = Naming::default();
+        assert_eq!(default.output_dir(input), Path::new("./ep01.seq"));
+        assert_eq!(default.sequence(input, 1, 42, None), "000042");
+
+        let naming = Naming::new(Path::new("/out"), "{path}", Some("{stem}-{index}-{frame}_{time}")).unwrap();
+        assert_eq!(naming.output_dir(input), Path::new("/out/media/show/s01/ep01"));
+        assert_eq!(naming.sequence(input, 3, 42, Some(Timestamp::new(1500, (1, 1000)))), "ep01-003-000042_00h00m01s500");
+
+        assert!(Naming::new(Path::new("."), "{frame}", None).is_err());
+        assert!(Naming::new(Path::new("."), "{stem}", Some("{stem")).is_err());
+        assert!(Naming::new(Path::new("."), "{stem}", Some("{stem}")).is_err());
+    }
+}
@@ -13,9 +13,15 @@ use std::path::*;
 use motion::vectors::{MVInfo,ToMotionVectors, MVec};
 use motion::search::{self, Estimate};
 use euclid::rect;
-use std::fs::{File,OpenOptions};
-use std::io::BufWriter;
 use std::cmp::max;
+use position::Timestamp;
+use profile::{Profile, Thresholds};
+use detect::{Detector, FrameStats, Action, PanEnd};
+use events::{Event, EventLog, Quantiles, Comparison};
+use manifest::Sequence;
+use error::{self, Error};
+use budget::{Job, Reservation};
+use std::sync::Arc;
 
 
 
@@ -24,16 +30,63 @@ pub(crate) struct MVFrame {
     mv_info: MVInfo,
     frame: frame::Video,
     idx: u32,
+    /// previous frame that was not collapsed into a held frame
+    pred_idx: Option<u32>,
+    /// number of decoded frames this frame stands for, >1 for held drawings
+    repeats: u32,
+    timestamp: Option<Timestamp>,
+    /// seconds since the predecessor, varies for VFR sources and held frames
+    interval: Option<f64>,
     frame_type: AVPictureType,
     motion_estimates: HashMap<u32, Estimate>,
     histogram: [u32; 256],
-    sar: ffmpeg::Rational
+    sar: ffmpeg::Rational,
+    /// released when the frame is dropped
+    _memory: Reservation
 }
 
 impl MVFrame {
-    pub fn new(mv_info: MVInfo, frame: Video, frame_type : AVPictureType, idx: u32, sar: ffmpeg::Rational) -> Self {
+    pub fn new(mv_info: MVInfo, frame: Video, frame_type : AVPictureType, idx: u32, timestamp: Option<Timestamp>, sar: ffmpeg::Rational, memory: Reservation) -> Self {
         //let idx = frame.display_number();
-        MVFrame { mv_info, frame, frame_type, idx, motion_estimates: HashMap::new(), histogram: [0 ; 256], sar }
+        MVFrame { mv_info, frame, frame_type, idx, pred_idx: None, repeats: 1, timestamp, interval: None, motion_estimates: HashMap::new(), histogram: [0 ; 256], sar, _memory: memory }
+    }
+
+    fn event(&self) -> Event {
+        let mut comparisons : Vec<_> = self.motion_estimates.iter().map(|(&idx, &est)| Comparison::new(idx, est)).collect();
+        comparisons.sort_by_key(|c| c.with);
+
+        Event::Frame {
+            idx: self.idx,
+            pred_idx: self.pred_idx,
+            pts: self.timestamp.map(|t| t.pts),
+            time: self.timestamp.map(|t| t.seconds()),
+            interval: self.interval,
+            frame_type: format!("{:?}", self.frame_type),
+            repeats: self.repeats,
+            luma: Quantiles {
+                min: self.min(),
+                q10: self.quantile(0.1),
+                q25: self.quantile(0.25),
+                q50: self.quantile(0.5),
+                q75: self.quantile(0.75),
+                q90: self.quantile(0.9),
+                max: self.max(),
+                avg: self.avg(),
+                mode: self.mode()
+            },
+            comparisons
+        }
+    }
+
+    /// what the pan detector gets to see of this frame
+    fn stats(&self) -> FrameStats {
+        FrameStats {
+            idx: self.idx,
+            pred_idx: self.pred_idx,
+            interval: self.interval,
+            luma_spread: self.quantile(0.9) - self.quantile(0.1),
+            estimate: self.predecessor_me()
+        }
     }
 
     fn res(&self) -> u32 {
@@ -48,6 +101,14 @@ impl MVFrame {
         self.motion_estimates.get(&frame_idx).cloned()
     }
 
+    /// pixel formats the histogram and motion search can handle
+    pub fn supported_format(format: Pixel) -> bool {
+        match format {
+            Pixel::YUV420P | Pixel::YUV444P | Pixel::YUV420P10LE | Pixel::YUV444P10LE => true,
+            _ => false
+        }
+    }
+
     pub fn calculate_histogram(&mut self) {
         let pixels = self.frame.data(0);
 
@@ -63,11 +124,15 @@ impl MVFrame {
     }
 
     pub fn predecessor_me(&self) -> Option<Estimate> {
-        if self.idx > 0 {
-            self.motion_estimates.get(&(self.idx - 1)).cloned()
-        } else {
-            None
-        }
+        self.pred_idx.and_then(|idx| self.full_compare(idx))
+    }
+
+    /// exact or near-exact repetition of the predecessor, e.g. animation on twos or a stepped camera move.
+    /// `subsample` is the `--sub` factor
+    fn is_held(&self, predecessor: &MVFrame, subsample: u8) -> bool {
+        let level = search::subsample_level(subsample, self.frame.height());
+        let est = search::error_sum(&predecessor.frame, &self.frame, 0, 0, level);
+        est.error_fraction() < 0.5 && est.max() <= 1
     }
 
 
@@ -103,9 +168,9 @@ impl MVFrame {
         self.histogram[..].into_iter().enumerate().map(|(i,v)| i as u32 * *v as u32).sum::<u32>() / max(self.hist_pop(),1)
     }
 
-    fn add_full_compare(&mut self, frame_idx: u32, est: Estimate) {
+    fn add_full_compare(&mut self, frame_idx: u32, est: Estimate, max_vector_error: f32) {
         if self.motion_estimates.insert(frame_idx, est) == None {
-            if est.error_fraction() < 5.0 && frame_idx < self.idx {
+            if est.error_fraction() < max_vector_error && frame_idx < self.idx {
                 let w = self.frame.width() as isize;
                 let h = self.frame.height() as isize;
                 let Estimate{x,y,..} = est;
@@ -120,7 +185,11 @@ impl MVFrame {
 
 impl Debug for MVFrame {
     fn fmt(&self, f: &mut Formatter) -> Result {
-        write!(f, "{} {} {:?} | {:0.3} {:?}", self.idx, self.frame.packet().dts , self.frame_type, self.predicted_fraction(), self.mv_info)?;
+        let ts = self.timestamp.map(|t| t.to_string()).unwrap_or("-".to_owned());
+        write!(f, "{} {} {:?} | {:0.3} {:?}", self.idx, ts, self.frame_type, self.predicted_fraction(), self.mv_info)?;
+        if self.repeats > 1 {
+            write!(f, " held x{}", self.repeats)?;
+        }
         write!(f, "\n hist: avg{} mode{} min{} 10th{} 25th{} 50th{} 75th{} 90th{} max{}",
                self.avg(), self.mode(), self.min(),  self.quantile(0.1), self.quantile(0.25), self.quantile(0.5), self.quantile(0.75), self.quantile(0.9), self.max())?;
         if !self.motion_estimates.is_empty() {
@@ -133,12 +202,14 @@ impl Debug for MVFrame {
 pub(crate) struct MVPrefilter {
     unprocessed: Vec<MVFrame>,
     processed: VecDeque<MVFrame>,
-    subsample: u8
+    subsample: u8,
+    collapse_held: bool,
+    profile: Profile
 }
 
 impl MVPrefilter {
-    pub fn new(subsampling: u8) -> Self {
-        MVPrefilter {unprocessed: vec![], processed: VecDeque::new(), subsample: subsampling}
+    pub fn new(subsampling: u8, collapse_held: bool, profile: Profile) -> Self {
+        MVPrefilter {unprocessed: vec![], processed: VecDeque::new(), subsample: subsampling, collapse_held, profile}
     }
 
     pub fn add_frames(&mut self, mut frames: &mut Vec<MVFrame>) {
@@ -148,7 +219,35 @@ impl MVPrefilter {
             f.calculate_histogram();
         });
 
-        self.unprocessed.extend(frames.drain(..));
+        let held : Vec<bool> = if self.collapse_held {
+            let new : &[MVFrame] = &frames[..];
+            let last = self.unprocessed.last();
+            let subsample = self.subsample;
+
+            (0..new.len()).into_par_iter().map(|i| {
+                let pred = if i == 0 { last } else { Some(&new[i - 1]) };
+                pred.map(|p| new[i].is_held(p, subsample)).unwrap_or(false)
+            }).collect()
+        } else {
+            vec![false; frames.len()]
+        };
+
+        for (mut f, held) in frames.drain(..).zip(held) {
+            if held {
+                if let Some(prev) = self.unprocessed.last_mut() {
+                    prev.repeats += f.repeats;
+                    continue;
+                }
+            }
+            if let Some(pred) = self.unprocessed.last() {
+                f.pred_idx = Some(pred.idx);
+                f.interval = match (f.timestamp, pred.timestamp) {
+                    (Some(a), Some(b)) => Some(a.seconds() - b.seconds()),
+                    _ => None
+                };
+            }
+            self.unprocessed.push(f);
+        }
 
         let estimates : Vec<_> =  self.unprocessed.par_windows(2).map(|window| {
             let ref predecessor = window[0];
@@ -161,12 +260,12 @@ impl MVPrefilter {
         for (ci, pi, est) in estimates {
             {
                 let current = self.unprocessed.iter_mut().find(|f| f.idx == ci).unwrap();
-                current.add_full_compare(pi, est);
+                current.add_full_compare(pi, est, self.profile.max_vector_error);
             }
 
             {
                 let pred = self.unprocessed.iter_mut().find(|f| f.idx == pi).unwrap();
-                pred.add_full_compare(ci, est.reverse());
+                pred.add_full_compare(ci, est.reverse(), self.profile.max_vector_error);
             }
         }
 
@@ -181,216 +280,294 @@ impl MVPrefilter {
         self.processed.pop_back()
     }
 
+    /// end of stream, includes the last frame which otherwise waits for a successor to compare against
     pub(crate) fn drain(mut self) -> impl Iterator<Item=MVFrame> {
+        for f in self.unprocessed.drain(..) {
+            self.processed.push_front(f);
+        }
         self.processed.into_iter().rev()
     }
 }
 
 use ::stitchers::linear::LinStitcher;
+use ::stitchers::spill::SpillCache;
+use ::stitchers::output;
+use clip::ClipOut;
 
 struct ImageOut {
     octx: ffmpeg::format::context::Output,
     encoder: ffmpeg::codec::encoder::video::Encoder,
     conv: Option<ffmpeg::software::scaling::context::Context>,
     start_frame: u32,
+    start_time: Option<Timestamp>,
+    name: String,
     last_frame_idx: u32,
+    last_time: Option<Timestamp>,
+    /// image2 file name pattern of the individual frames
+    pattern: String,
+    frames_written: u32,
     stitcher: LinStitcher,
     dir: PathBuf,
     next_frame: Option<MVFrame>,
-    log: Option<BufWriter<File>>,
+    clip: Option<ClipOut>,
 }
 
 impl ImageOut {
-    fn to_stitcher(mut self) -> LinStitcher {
+    fn to_stitcher(self, detect_loops: bool) -> LinStitcher {
         let mut st = self.stitcher;
-        if let Some(log) = self.log.as_mut() {
-            writeln!(log, "stitch\n{:?}", st);
+        if detect_loops {
+            st.detect_period();
         }
 
-        st.set_start_frame(self.start_frame);
+        st.set_name(self.name);
         st
     }
 
-    fn next_frame(&mut self, frame: MVFrame, stitch: bool) {
-        assert!(self.next_frame.is_none());
+    fn next_frame(&mut self, frame: MVFrame, stitch: bool, job: &Job) -> ::std::result::Result<(), Error> {
+        if self.next_frame.is_some() {
+            return Err(format!("frame {} added before the previous one was encoded", frame.idx).into());
+        }
         self.last_frame_idx = frame.idx;
+        self.last_time = frame.timestamp;
 
-        let est = if frame.idx > 0 {
-            frame.full_compare(frame.idx - 1)
-        } else {
-            None
-        };
+        let est = frame.predecessor_me();
         if stitch {
-            self.stitcher.add_frame(frame.frame.clone(), est, frame.sar);
+            // the copy outlives the frame, it needs its own share of the budget
+            let memory = job.reserve_frame(&frame.frame);
+            self.stitcher.add_frame(frame.idx, frame.timestamp.map(|t| t.pts), frame.frame.clone(), est, frame.sar, Some(memory))?;
         }
         self.next_frame = Some(frame);
+        Ok(())
     }
 
-    fn encode(&mut self, format: Format) {
+    fn encode(&mut self, format: Format) -> ::std::result::Result<(), Error> {
         let mv_frame = match ::std::mem::replace(&mut self.next_frame, None) {
             Some(f) => f,
-            None => return
+            None => return Ok(())
         };
 
-        let mut packet = ffmpeg::codec::packet::packet::Packet::empty();
-
-        if let Some(log) = self.log.as_mut() {
-            writeln!(log,"{:?}", mv_frame).unwrap();
+        let failed = match self.clip {
+            Some(ref mut clip) => clip.encode(&mv_frame.frame, mv_frame.timestamp.map(|t| t.pts)).err(),
+            None => None
+        };
+        if let Some(e) = failed {
+            self.drop_clip(e);
         }
 
+        let mut packet = ffmpeg::codec::packet::packet::Packet::empty();
 
         if let Format::NULL = format {
-            return;
+            return Ok(());
         }
 
         let frame_in = mv_frame.frame;
-        let frame_out = if let Some(ref mut conv) = self.conv {
+        let mut frame_out = if let Some(ref mut conv) = self.conv {
             let mut frame_out = ffmpeg::frame::Video::new(format.pixel_format(), frame_in.width(),frame_in.height());
-            conv.run(&frame_in, &mut frame_out).unwrap();
+            conv.run(&frame_in, &mut frame_out)?;
             frame_out
         } else {
             frame_in
         };
+        frame_out.set_pts(mv_frame.timestamp.map(|t| t.pts));
 
-        match self.encoder.encode(&frame_out, &mut packet) {
-            Ok(true) => {
-                write_packet(self, packet)
-            }
-            Ok(false) => {
-            }
-            Err(e) => {
-                println!("{:?}", e);
-                return;
+        let full = self.encoder.encode(&frame_out, &mut packet)?;
+        self.frames_written += 1;
+        if full {
+            write_packet(self, packet)?;
+        }
+        Ok(())
+    }
+
+    /// file name of the `i`th single frame, counted from 1
+    fn frame_image(&self, i: u32) -> String {
+        // the sequence name before it may contain anything, including %03d
+        match self.pattern.rfind("%03d") {
+            Some(at) => format!("{}{:03}{}", &self.pattern[..at], i, &self.pattern[at + 4..]),
+            None => self.pattern.clone()
+        }
+    }
+
+    /// the clip is an extra, its failure leaves the stills and the composite alone
+    fn drop_clip(&mut self, e: Error) {
+        if let Some(clip) = self.clip.take() {
+            eprintln!("{}: dropping clip {}: {}", self.name, clip.file, e);
+            let _ = ::std::fs::remove_file(self.dir.join(&clip.file));
+        }
+    }
+
+    /// last frame, encoder flush and trailer
+    fn finish(&mut self, format: Format) -> ::std::result::Result<(), Error> {
+        self.encode(format)?;
+
+        if format  != Format::NULL {
+            loop {
+                let mut packet = ffmpeg::codec::packet::packet::Packet::empty();
+                match self.encoder.flush(&mut packet) {
+                    Ok(true) => write_packet(self, packet)?,
+                    _ => break
+                }
             }
+            self.octx.write_trailer()?;
         }
+
+        let failed = match self.clip {
+            Some(ref mut clip) => clip.finish().err(),
+            None => None
+        };
+        if let Some(e) = failed {
+            self.drop_clip(e);
+        }
+
+        if self.last_frame_idx < self.start_frame {
+            return Err(format!("created an out without frame {} {}", self.last_frame_idx, self.start_frame).into());
+        }
+
+        Ok(())
     }
 
 }
 
-
 pub(crate) struct PanFinder {
     frame_nr: usize,
     frames: VecDeque<MVFrame>,
+    detector: Detector,
     out: Option<ImageOut>,
-    log: Option<BufWriter<File>>,
+    log: Option<EventLog>,
+    input: PathBuf,
     output_path: PathBuf,
-    pub image_batches: Vec<LinStitcher>,
-    config: ::Config
+    /// sequences started so far, including those of an earlier run that is being resumed
+    opened: usize,
+    /// closed pans and their stitcher, None if there is no composite to write
+    pub finished: Vec<(Sequence, Option<LinStitcher>)>,
+    config: ::Config,
+    thresholds: Thresholds,
+    job: Arc<Job>
 }
 
-fn write_packet(out: &mut ImageOut, mut packet: ffmpeg::packet::Packet) {
+fn write_packet(out: &mut ImageOut, mut packet: ffmpeg::packet::Packet) -> ::std::result::Result<(), Error> {
     //let mut packet = self.packets.pop_back().unwrap();
     //let mut out = self.out.as_mut().unwrap();
     packet.set_stream(0);
     //packet.set_pts(Some(mv_frame.idx as i64));
     //packet.set_dts(Some(mv_frame.idx as i64));
 
-    out.octx.write_header().unwrap();
-    packet.write(&mut out.octx).unwrap();
-
-}
-
-#[derive(Debug)]
-enum Run {
-    Still,
-    SceneChange,
-    Run {motion_frames: usize,oldest_frame: usize, end: RunEnd},
-}
-
-#[derive(Debug)]
-enum RunEnd {
-    DirectionChange,
-    OutOfFrames,
-    MismatchAfterStills,
-    SceneChange,
-    LowEntropyFrame
-}
-
-#[derive(Debug)]
-enum PanEnd {
-    Scenechange,
-    QueueSaturation,
-    RunDiscontinuity(Run),
-    EndOfStream
+    out.octx.write_header()?;
+    packet.write(&mut out.octx)?;
+    Ok(())
 }
 
 impl PanFinder {
-    pub fn new(output_path: PathBuf, config: ::Config) -> Self {
-        ::std::fs::create_dir_all(&output_path).unwrap();
+    pub fn new(input: &Path, config: ::Config, job: Arc<Job>, resumed: usize, fps: f64, width: u32, height: u32) -> ::std::result::Result<Self, Error> {
+        let output_path = config.naming.output_dir(input);
+        ::std::fs::create_dir_all(&output_path).map_err(Error::io(&output_path))?;
 
-        let log_path = output_path.join("frames.log");
         let log = if config.log {
-            Some(BufWriter::new(OpenOptions::new().write(true).create(true).open(log_path).unwrap()))
+            let path = output_path.join("frames.jsonl");
+            let log = if resumed > 0 { EventLog::append(&path) } else { EventLog::create(&path) };
+            let mut log = log.map_err(Error::io(&path))?;
+            log.write(&Event::Stream{fps, width, height, profile: config.profile}).map_err(Error::io(&path))?;
+            Some(log)
         } else {
             None
         };
 
-        PanFinder {frame_nr: 0, frames: VecDeque::new(), out: None, output_path, image_batches: vec![], log, config}
+        let thresholds = config.profile.thresholds(fps, width, height);
+
+        Ok(PanFinder {frame_nr: 0, frames: VecDeque::new(), detector: Detector::new(thresholds), out: None, input: input.to_owned(), output_path, opened: resumed, finished: vec![], log, config, thresholds, job})
     }
 
-    pub fn add_frame(&mut self, frame: MVFrame) {
+    /// a failing log only gets reported once, the outputs don't depend on it
+    fn log<F: FnOnce() -> Event>(&mut self, event: F) {
+        let failed = match self.log {
+            Some(ref mut log) => log.write(&event()).err(),
+            None => None
+        };
 
-        if frame.idx > 0 {
-            if let (Some(est), Some(log)) = (frame.full_compare(frame.idx - 1), self.log.as_mut()) {
-                writeln!(log, "{:?}", frame);
+        if let Some(e) = failed {
+            eprintln!("{}: writing frames.jsonl failed, logging disabled: {}", self.input.display(), e);
+            self.log = None;
+        }
+    }
+
+    pub fn add_frame(&mut self, mut frame: MVFrame) -> ::std::result::Result<(), Error> {
+        // the prefilter already compared most frames with their predecessor
+        if frame.predecessor_me().is_none() {
+            if let Some(pred) = self.frames.front_mut() {
+                if frame.pred_idx == Some(pred.idx) {
+                    PanFinder::compare_frames(&mut frame, pred, self.thresholds.max_vector_error);
+                }
             }
         }
 
+        self.log(|| frame.event());
+
         self.frame_nr += 1;
+        let stats = frame.stats();
         self.frames.push_front(frame);
 
+        let actions = self.detector.push(stats);
 
-        const MAX_QUEUE : usize = 24;
-
-        if self.frames.len() > MAX_QUEUE {
-            self.frames.pop_back();
-            assert!(self.out.is_none(), "queue overflow only allowed when no batch present");
-        }
-
+        let run = self.detector.last_run();
+        self.log(|| Event::Run{idx: stats.idx, run});
 
-        let mut oldest_queued_idx = self.frames[self.frames.len()-1].idx as usize;
+        self.apply(actions)
+    }
 
-        if let Some(out) = self.out.as_ref() {
-            let last_in_encoder = out.last_frame_idx as usize;
-            assert!(oldest_queued_idx - 1 == last_in_encoder, "queue discontinuity. queue: {:?}\nencoder:{}", self.frames, last_in_encoder);
-            oldest_queued_idx = last_in_encoder;
-        }
 
+    pub fn close(mut self) -> ::std::result::Result<Vec<(Sequence, Option<LinStitcher>)>, Error> {
+        let actions = self.detector.finish();
+        self.apply(actions)?;
+        Ok(self.finished)
+    }
 
-        let run = self.run_length();
-        match run {
-            Run::SceneChange => {
-                self.finish_batch(PanEnd::Scenechange);
-            }
-            Run::Run{oldest_frame, ..} => {
-                if oldest_frame != oldest_queued_idx {
-                    self.finish_batch(PanEnd::RunDiscontinuity(run));
+    /// errors only concern the open sequence, the detector keeps going. Err if the queue is out of sync with it
+    fn apply(&mut self, actions: Vec<Action>) -> ::std::result::Result<(), Error> {
+        for action in actions {
+            match action {
+                Action::Drop(idx) => {
+                    self.pop_frame(idx)?;
+                }
+                Action::Open{start_frame, run} => {
+                    self.log(|| Event::PanStart{start_frame, run});
+                    if let Err(e) = self.open_batch(start_frame) {
+                        eprintln!("{}: skipping sequence at frame {}: {}", self.input.display(), start_frame, e);
+                    }
                 }
+                Action::Append(idx) => {
+                    let frame = self.pop_frame(idx)?;
+                    let (format, stitch) = (self.config.single_frame_format, self.config.stitch);
+                    // without an out the sequence was abandoned and its frames are discarded until the detector closes it
+                    let result = match self.out {
+                        Some(ref mut out) => {
+                            let job = &self.job;
+                            out.encode(format).and_then(|_| out.next_frame(frame, stitch, job))
+                        }
+                        None => Ok(())
+                    };
+                    if let Err(e) = result {
+                        self.abandon_batch(e);
+                    }
+                }
+                Action::Close(reason) => self.finish_batch(reason)
             }
-            _ => {}
         }
 
-        if self.frames.len() == MAX_QUEUE {
-            self.finish_batch(PanEnd::QueueSaturation);
-        }
-
-
-        if let run @ Run::Run{..} = self.run_length() {
-            self.try_open_batch(run);
+        Ok(())
+    }
 
-            if let Some(ref mut out) = self.out {
-                while self.frames.len() > 0 {
-                    out.encode(self.config.single_frame_format);
-                    out.next_frame(self.frames.pop_back().unwrap(),self.config.stitch);
-                }
-            }
+    /// the oldest queued frame, which is the one the detector decided about
+    fn pop_frame(&mut self, idx: u32) -> ::std::result::Result<MVFrame, Error> {
+        let frame = self.frames.pop_back().ok_or_else(|| format!("frame queue empty, expected frame {}", idx))?;
+        if frame.idx != idx {
+            return Err(format!("frame queue discontinuity, expected frame {} but got {}", idx, frame.idx).into());
         }
+        Ok(frame)
     }
 
-
-    pub fn close(mut self) -> Vec<LinStitcher> {
-        self.finish_batch(PanEnd::EndOfStream);
-        self.image_batches
+    fn abandon_batch(&mut self, e: Error) {
+        if let Some(out) = ::std::mem::replace(&mut self.out, None) {
+            eprintln!("{}: dropping sequence {}: {}", self.input.display(), out.name, e);
+        }
     }
 
     fn finish_batch(&mut self, reason: PanEnd) {
@@ -398,40 +575,60 @@ impl PanFinder {
 
         if let Some(mut out) = out {
 
-            let format = self.config.single_frame_format;
-            // last frame if there is one
-            out.encode(format);
-
-            if format  != Format::NULL {
-                loop {
-                    let mut packet = ffmpeg::codec::packet::packet::Packet::empty(); //std::mem::replace(&mut self.cur_packet, None).unwrap();
-                    match out.encoder.flush(&mut packet) {
-                        Ok(true) => write_packet(&mut out, packet),
-                        _ => break
-                    }
-                }
-                out.octx.write_trailer().unwrap();
+            if let Err(e) = out.finish(self.config.single_frame_format) {
+                self.out = Some(out);
+                self.abandon_batch(e);
+                return;
             }
 
-            if let Some(log) = out.log.as_mut() {
-                writeln!(log, "{:?}", reason);
+            let name = out.name.clone();
+            let frame_images = (1 .. out.frames_written + 1).map(|i| out.frame_image(i)).collect();
+            let clip = out.clip.as_ref().map(|c| c.file.clone());
+            let (start_frame, mut end_frame) = (out.start_frame, out.last_frame_idx);
+            let (start_time, mut end_time) = (out.start_time.map(|t| t.seconds()), out.last_time.map(|t| t.seconds()));
+            let time_base = out.start_time.map(|t| t.time_base);
 
-                if self.frames.len() > 0 {
-                    for frame in self.frames.iter().rev() {
-                        writeln!(log, "pan end: {:?}", frame);
-                    }
-                } else {
-                    writeln!(log, "pan end, no more frames");
+            let stitcher = out.to_stitcher(self.config.loops);
+
+            // a cycling background drops the frames after its repetition
+            if stitcher.period().is_some() {
+                if let Some((idx, pts)) = stitcher.last_frame() {
+                    end_frame = idx;
+                    end_time = pts.and_then(|pts| time_base.map(|tb| Timestamp::new(pts, tb).seconds()));
                 }
             }
 
-            assert!(out.last_frame_idx >= out.start_frame, "created an out without frame {} {}", out.last_frame_idx, out.start_frame);
+            self.log(|| Event::PanEnd{reason, period: stitcher.period()});
+
+            let stitched = self.config.stitch && stitcher.sar().is_some();
+            let keep = stitched && stitcher.expansion_ratio() < self.config.min_expand;
+
+            let sequence = Sequence {
+                name,
+                start_frame,
+                end_frame,
+                start_time,
+                end_time,
+                end_reason: reason,
+                sar: stitcher.sar().map(|r| (r.numerator(), r.denominator())).unwrap_or((1, 1)),
+                canvas: if stitched { Some(stitcher.canvas_size()) } else { None },
+                expansion_ratio: if stitched { Some(stitcher.expansion_ratio()) } else { None },
+                period: stitcher.period(),
+                frames: stitcher.placements(),
+                composites: if keep { stitcher.composite_names() } else { vec![] },
+                frame_images,
+                clip,
+                stabilized: if keep { stitcher.stabilized_name() } else { None },
+                trajectory: if keep { stitcher.trajectory_names() } else { vec![] },
+                // known to the decoding thread only
+                fields: None
+            };
 
-            self.image_batches.push(out.to_stitcher());
+            self.finished.push((sequence, if keep { Some(stitcher) } else { None }));
         }
     }
 
-    fn compare_frames(newer: &mut MVFrame, older: &mut MVFrame) -> Estimate {
+    fn compare_frames(newer: &mut MVFrame, older: &mut MVFrame, max_vector_error: f32) -> Estimate {
         if let Some(est) =  newer.full_compare(older.idx) {
             return est;
         }
@@ -439,281 +636,37 @@ impl PanFinder {
         let hint = newer.frame.most_common_vectors().or_else(|| newer.frame.most_common_vectors());
         let estimate = search::search(&newer.frame, &older.frame, hint, 0);
 
-        newer.add_full_compare(older.idx, estimate);
-        older.add_full_compare(newer.idx, estimate.reverse());
+        newer.add_full_compare(older.idx, estimate, max_vector_error);
+        older.add_full_compare(newer.idx, estimate.reverse(), max_vector_error);
         estimate
     }
 
-    fn run_length(&mut self) -> Run {
-
-        if self.frames.len() < 1 {
-            return Run::Still;
-        }
-
-        /*
-         * goals
-         * - find some run of linear motion
-         * - extend scene around motion forwards and backwards through non-scene change still frames
-         * - be robust against I- and low-predicted P-frames
-         * - be robust against still frames/motion stutter
-         * - handle changes of direction as long as frames during directional change are highly predicted
-         *   e.g. if there is a gap in our motion knowledge look for continuity. otherwise look for motion + prediction between frames
-         */
-
-
-        let mut motion_frames = 0;
-        let mut last = self.frames[0].idx as usize;
-
-        let mut frame_refs : Vec<&mut MVFrame> = vec![];
-        let (a,b) = self.frames.as_mut_slices();
-        frame_refs.extend(a);
-        frame_refs.extend(b);
-        if let Some(out) = self.out.as_mut() {
-            if let Some(last) = out.next_frame.as_mut() {
-                frame_refs.push(last);
-            }
-        }
-
-        if frame_refs.len() < 2 {
-            return Run::Still;
-        }
-
-        let mut end_reason = RunEnd::OutOfFrames;
-        let mut mvec = MVec::new();
-
-        {
-            let ref current = frame_refs[0];
-            let ref pred = frame_refs[1];
-
-            match current.full_compare(current.idx - 1) {
-                Some(est) => {
-                    if let Some(pred_est) = pred.predecessor_me() {
-                        if (pred_est.error_fraction() - est.error_fraction()).abs() > 6.5 {
-                            return Run::SceneChange;
-                        }
-                    }
-                    mvec = mvec.from_vector(est.x, est.y);
-                    if est.quantile(0.75) >= 10 {
-                        if let Some(pred_est) = pred.predecessor_me() {
-                            let vec = MVec::new().from_vector(pred_est.x, pred_est.y);
-                            if !vec.is_similar(&mvec) {
-                                return Run::SceneChange;
-                            }
-                        } else {
-                            return Run::SceneChange;
-                        }
-                    }
-                    if est.x == 0 && est.y == 0 {
-                        return Run::Still;
-                    }
-                }
-                None => return Run::Still
-            }
-        }
-
-        for frame_idx in 1..frame_refs.len() /* self.frames.iter().skip(1).chain(self.out.as_ref().and_then(|o| o.next_frame.as_ref()).into_iter()) */ {
-            let (successors,predecessors) = frame_refs.split_at_mut(frame_idx);
-            let (current,predecessors) = predecessors.split_at_mut(1);
-            let ref mut current = current[0];
-
-            if current.quantile(0.9) - current.quantile(0.1) <= 35 {
-                end_reason = RunEnd::LowEntropyFrame;
-                break;
-            }
-
-            let successor_estimate = {
-                let newer_idx = successors.len()-1;
-                let ref mut newer = successors[newer_idx];
-                PanFinder::compare_frames(newer, current)
-            };
-
-            let vec = MVec::new().from_vector(successor_estimate.x, successor_estimate.y);
-            if successor_estimate.quantile(0.75) >= 10 && !vec.is_similar(&mvec) {
-                end_reason = RunEnd::SceneChange;
-                break
-            }
-
-            let current_idx = current.idx as usize;
-
-            last = current_idx;
-            if successor_estimate.x != 0 || successor_estimate.y != 0 {
-                mvec = vec;
-                motion_frames += 1;
-            }
-
-            // probably redundant given the same check above the loop
-            if let Some(predecessor_estimate) = current.predecessor_me() {
-                if (predecessor_estimate.error_fraction() - successor_estimate.error_fraction()).abs() > 6.5 {
-                    end_reason = RunEnd::SceneChange;
-                    break;
-                }
-            }
-        }
-
-
-        if motion_frames == 0 {
-            return Run::Still;
-        }
-
-        return Run::Run{motion_frames, oldest_frame: last, end: end_reason};
-
-
-
-        /*
-        let initial_still = (&self.frames[0].mv_info).still_blocks(::motion::vectors::Direction::Forward);
-        let mut prev_motion = (&self.frames[0].mv_info).dominant_angle(::motion::vectors::Direction::Forward);
-
-        let mut motion_frames = 0;
-        let mut last = self.frames[0].idx as usize;
-
-        // we need motion to start a search for similar vectors
-        if prev_motion == (0,0) || initial_still >= prev_motion.1 {
-            return Run::Still;
-        }
-
-        let mut frame_refs : Vec<&mut MVFrame> = vec![];
-        let (a,b) = self.frames.as_mut_slices();
-        frame_refs.extend(a);
-        frame_refs.extend(b);
-        if let Some(out) = self.out.as_mut() {
-            if let Some(last) = out.next_frame.as_mut() {
-                frame_refs.push(last);
-            }
-        }
-
-        let mut end_reason = RunEnd::OutOfFrames;
-
-        for frame_idx in 1..frame_refs.len() /* self.frames.iter().skip(1).chain(self.out.as_ref().and_then(|o| o.next_frame.as_ref()).into_iter()) */ {
-            let (newer,older) = frame_refs.split_at_mut(frame_idx);
-            let (frame,older) = older.split_at_mut(1);
-            let ref mut frame = frame[0];
-
-            let frac = frame.predicted_fraction();
-            let past = frame.mv_info.past();
-            let future = frame.mv_info.future();
-            let still = frame.mv_info.still_blocks(::motion::vectors::Direction::Backward);
-            let motion = frame.mv_info.dominant_angle(::motion::vectors::Direction::Backward);
-
-
-            // skip highly predicted still frames in the middle of pans
-            // compare against next moving one
-            // B-frames get bi-predicted and we transplant onto P/I frames
-            // so "highly predicted" generally means more than 1.0
-            if frac > 1.2 && still >= motion.1 * 2 {
-                continue;
-            }
-
-            // potential scene change
-            if past * 8 < future {
-                let idx = newer.len()-1;
-                let ref mut newer = newer[idx];
-                let est = PanFinder::compare_frames(newer, frame);
-                let vec = MVec::new().from_vector(est.x, est.y);
-                if est.error_fraction() > 5.0 && (vec.angle() - prev_motion.0).abs() > 23 {
-                    return Run::Scenechange
-                }
-            }
-
-
-            if (motion.0 - prev_motion.0).abs() > 23 {
-                let idx = newer.len()-1;
-                let ref mut newer = newer[idx];
-                let est = PanFinder::compare_frames(newer, frame);
-                let vec = MVec::new().from_vector(est.x, est.y);
-                if est.error_fraction() > 5.0 && (vec.angle() - prev_motion.0).abs() > 23 {
-                    end_reason = RunEnd::DirectionChange;
-                    break;
-                }
-            }
-
-            let current = frame.idx as usize;
-
-            // skipped frames, verify that it's still the same scene
-            if current + 1 != last {
-
-                let last_idx = match newer.iter().position(|f| f.idx == last as u32) {
-                    Some(i) => i,
-                    None => {
-                        panic!("expected to find {} avail:{:?}; cur:{}", last, newer.iter().map(|n| n.idx).collect::<Vec<_>>(), current)
-                    }
-                };
-                let ref mut newer = newer[last_idx];
-                let est = PanFinder::compare_frames(newer, frame);
-                if est.error_fraction() > 5.0 {
-                    end_reason = RunEnd::MismatchAfterStills;
-                    break
-                }
-            }
-
-            prev_motion = motion;
-            last = current;
-            motion_frames += 1;
-        }
-
-        if motion_frames == 0 {
-            return Run::Still;
-        }
-
-        return Run::Run{motion_frames, oldest_frame: last, end: end_reason};
-        */
-    }
-
-    pub fn output_path_from_input(p: &Path) -> PathBuf {
-        let mut dir = PathBuf::from(".");
-        dir.push(Path::new(p.file_stem().unwrap()));
-        dir.set_extension("seq");
-        dir
-    }
-
-    fn try_open_batch(&mut self, run: Run) {
+    fn open_batch(&mut self, start_frame: u32) -> ::std::result::Result<(), Error> {
         if self.out.is_some() {
-            return;
-        }
-
-        let run_info = match run {
-            Run::Run{motion_frames, oldest_frame, ..} => (motion_frames, oldest_frame),
-            _ => return
-        };
-
-        if run_info.0 < 6 {
-            return;
+            return Err("pan already open".into());
         }
 
-        let start_frame = run_info.1;
+        let start_time = self.frames.iter().find(|f| f.idx == start_frame).and_then(|f| f.timestamp);
+        self.opened += 1;
+        let name = self.config.naming.sequence(&self.input, self.opened, start_frame, start_time);
 
         let dir = self.output_path.to_owned();
-        ::std::fs::create_dir_all(&dir).unwrap();
-
-        // /foo/bar/video.mkv -> video -> ./video.seq/XXXXXX+YYY.png
+        ::std::fs::create_dir_all(&dir).map_err(Error::io(&dir))?;
 
-        let logname = dir.join(format!("{:06}.log", start_frame));
-        let mut log = if self.config.log {
-            Some(::std::io::BufWriter::new(::std::fs::OpenOptions::new().create(true).write(true).truncate(true).open(logname).unwrap()))
-        } else {
-            None
-        };
-
-        let to_drop : usize = self.frames[0].idx as usize - start_frame + 1;
-
-
-        for discard in self.frames.drain(to_drop..).rev() {
-            if let Some(log) = log.as_mut() { writeln!(log, "pre-pan: {:?}", discard).unwrap();}
-            /*
-            if let Some(mvs) = discard.frame.motion_vecs() {
-                writeln!(log, "mv: {:?}", mvs);
-            }*/
-        }
+        // /foo/bar/video.mkv -> video -> ./video.seq/XXXXXX_HHhMMmSSsMMM+YYY.png
 
-        if let Some(log) = log.as_mut() {writeln!(log, "{:?}", run);}
+        let to_drop : usize = self.frames.iter().position(|f| f.idx < start_frame).unwrap_or(self.frames.len());
+        self.frames.truncate(to_drop);
 
         let format = self.config.single_frame_format;
 
-        let image2format = format!("{:06}+%03d.{}", start_frame, format.extension());
-        let p = dir.join(image2format);
+        let image2format = format!("{}+%03d.{}", name, format.extension());
+        // image2 expands % anywhere in the path
+        let p = PathBuf::from(format!("{}+%03d.{}", error::ffmpeg_path(&dir.join(&name))?.replace('%', "%%"), format.extension()));
 
         let mut octx = unsafe {
             let mut ps     = ::std::ptr::null_mut();
-            let     path   = ::std::ffi::CString::new(p.as_os_str().to_str().unwrap()).unwrap();
+            let     path   = ::std::ffi::CString::new(error::ffmpeg_path(&p)?).map_err(|_| format!("{}: path contains a nul byte", p.display()))?;
             let     format = ::std::ffi::CString::new("image2").unwrap();
 
             match ffmpeg::ffi::avformat_alloc_output_context2(&mut ps, ::std::ptr::null_mut(), format.as_ptr(), path.as_ptr()) {
@@ -725,49 +678,76 @@ impl PanFinder {
 
                 e => Err(ffmpeg::Error::from(e))
             }
-        }.unwrap();
+        }?;
 
 
+        let time_base = start_time.map(|t| t.time_base).unwrap_or((24, 1000));
+
         // TODO: simplify cargo-culted code
-        let codec = ffmpeg::encoder::find_by_name(format.codec()).unwrap();
+        let codec = ffmpeg::encoder::find_by_name(format.codec()).ok_or(format!("no {} encoder", format.codec()))?;
         let mut encoder = {
-            let mut output = octx.add_stream(codec).unwrap();
-            output.set_time_base((24, 1000));
+            let mut output = octx.add_stream(codec)?;
+            output.set_time_base(time_base);
             output.codec().set_threading(threading::Config{kind: threading::Type::Frame, count: 0, safe: true});
             output.codec().encoder()
         };
 
-        encoder.set_time_base((24, 1000));
+        encoder.set_time_base(time_base);
         encoder.set_threading(threading::Config{kind: threading::Type::Frame, count: 0, safe: true});
 
-        let mut encoder = encoder.video().unwrap();
+        let mut encoder = encoder.video()?;
         let frame = &self.frames[0].frame;
         encoder.set_width(frame.width());
         encoder.set_height(frame.height());
         encoder.set_format(format.pixel_format());
-        encoder.set_global_quality(2);
-        encoder.set_qmin(1);
-        encoder.set_qmax(3);
-        let mut encoder = encoder.open_as(codec).unwrap();
+        let options = format.configure(&mut encoder, self.config.quality);
+        let mut encoder = encoder.open_as_with(codec, options)?;
         encoder.set_format(format.pixel_format());
 
         let conv = if format.pixel_format() != Pixel::YUV420P {
-            Some(converter((frame.width(),frame.height()), frame.format(), format.pixel_format()).unwrap())
+            Some(converter((frame.width(),frame.height()), frame.format(), format.pixel_format())?)
         } else {
             None
         };
 
-        self.out = Some(ImageOut { next_frame: None, log, octx: octx, encoder: encoder, start_frame: start_frame as u32, last_frame_idx: 0, conv: conv, stitcher: LinStitcher::new(), dir: dir })
+        let fps = 1.0 / self.thresholds.frame_duration;
+        let clip = match self.config.clip {
+            Some(clip) => match ClipOut::open(&dir, &name, clip, frame, self.frames[0].sar, start_time.map(|t| t.time_base), fps) {
+                Ok(clip) => Some(clip),
+                Err(e) => {
+                    eprintln!("{}: no clip for sequence {}: {}", self.input.display(), name, e);
+                    None
+                }
+            },
+            None => None
+        };
+
+        let mut stitcher = LinStitcher::new();
+        stitcher.set_layers(self.config.layers as usize);
+        stitcher.set_layout(self.config.layout, self.config.tile_size);
+        stitcher.set_encoding(self.config.composite_format, self.config.composite_quality);
+        stitcher.set_timing(time_base, fps);
+        stitcher.set_trajectory(self.config.trajectory);
+        if let Some(clip) = self.config.stabilize {
+            stitcher.set_stabilized(clip, self.config.backdrop);
+        }
+        if let Some(limit) = self.config.stitch_memory {
+            stitcher.set_spill(SpillCache::new(&::std::env::temp_dir(), limit));
+        }
+
+        self.out = Some(ImageOut { next_frame: None, last_time: None, pattern: image2format, frames_written: 0, octx: octx, encoder: encoder, start_frame, start_time, name, last_frame_idx: 0, conv: conv, stitcher, dir: dir, clip });
+        Ok(())
     }
 
 
 
 }
 
+// PNG16 keeps 10 bit sources at full depth, WEBP is lossless unless --quality is given
 arg_enum!{
     #[derive(Copy, Clone, PartialEq)]
     pub enum Format {
-        PNG, JPG, NULL
+        PNG, PNG16, JPG, WEBP, TIFF, NULL
     }
 }
 
@@ -776,15 +756,21 @@ impl Format {
         match self {
             Format::NULL => "png",
             Format::PNG => "png",
-            Format::JPG => "jpg"
+            Format::PNG16 => "png",
+            Format::JPG => "jpg",
+            Format::WEBP => "webp",
+            Format::TIFF => "tiff"
         }
     }
 
-    fn codec(self) -> &'static str {
+    pub fn codec(self) -> &'static str {
         match self {
             Format::NULL => "png",
             Format::PNG => "png",
-            Format::JPG => "mjpeg"
+            Format::PNG16 => "png",
+            Format::JPG => "mjpeg",
+            Format::WEBP => "libwebp",
+            Format::TIFF => "tiff"
         }
     }
 
@@ -792,7 +778,44 @@ impl Format {
         match self {
             Format::NULL => Pixel::RGBA,
             Format::PNG => Pixel::RGBA,
+            Format::PNG16 => Pixel::RGB48BE,
             Format::JPG => Pixel::YUVJ420P,
+            Format::WEBP => Pixel::BGRA,
+            Format::TIFF => Pixel::RGB24
+        }
+    }
+
+    fn configure(self, encoder: &mut ffmpeg::encoder::video::Video, quality: Option<u8>) -> ffmpeg::Dictionary<'static> {
+        output::configure_image(self.codec(), encoder, quality)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MVFrame;
+    use budget::Budget;
+    use ffmpeg;
+    use ffmpeg::ffi::AVPictureType;
+    use ffmpeg::frame::Video;
+    use ffmpeg::util::format::pixel::Pixel;
+    use motion::vectors::MVInfo;
+
+    fn frame(idx: u32) -> MVFrame {
+        let mut frame = Video::new(Pixel::YUV420P, 256, 128);
+        for i in 0..frame.planes() {
+            for (j, b) in frame.data_mut(i).iter_mut().enumerate() {
+                *b = (j * 7 % 251) as u8;
+            }
+        }
+        let job = Budget::admit(&Budget::new(1 << 30));
+        MVFrame::new(MVInfo::new(), frame, AVPictureType::AV_PICTURE_TYPE_P, idx, None, ffmpeg::Rational(1, 1), job.reserve(0))
+    }
+
+    #[test]
+    fn held_with_any_subsampling() {
+        let (first, second) = (frame(0), frame(1));
+        for &sub in &[0, 1, 2, 4, 8] {
+            assert!(second.is_held(&first, sub));
         }
     }
 }
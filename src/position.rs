@@ -0,0 +1,123 @@
+use std::str::FromStr;
+use std::fmt;
+
+/// A point or span in a video given on the command line, either as frame number or as `[[hh:]mm:]ss[.ms]`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Position {
+    Frame(u32),
+    Time(f64)
+}
+
+impl Position {
+    pub fn seconds(self, fps: f64) -> f64 {
+        match self {
+            Position::Frame(n) => n as f64 / fps,
+            Position::Time(s) => s
+        }
+    }
+
+    pub fn frames(self, fps: f64) -> u32 {
+        match self {
+            Position::Frame(n) => n,
+            Position::Time(s) => (s * fps).round() as u32
+        }
+    }
+}
+
+/// Presentation timestamp of a frame in the time base of its stream
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Timestamp {
+    pub pts: i64,
+    pub time_base: (i32, i32)
+}
+
+impl Timestamp {
+    pub fn new(pts: i64, time_base: (i32, i32)) -> Self {
+        Timestamp {pts, time_base}
+    }
+
+    pub fn seconds(&self) -> f64 {
+        self.pts as f64 * self.time_base.0 as f64 / self.time_base.1 as f64
+    }
+
+    fn split(&self) -> (u64, u64, u64, u64) {
+        let ms = (self.seconds().max(0.0) * 1000.0).round() as u64;
+        (ms / 3_600_000, ms / 60_000 % 60, ms / 1000 % 60, ms % 1000)
+    }
+
+    /// like the Display output, but without characters that are awkward in file names
+    pub fn file_name(&self) -> String {
+        let (h, m, s, ms) = self.split();
+        format!("{:02}h{:02}m{:02}s{:03}", h, m, s, ms)
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (h, m, s, ms) = self.split();
+        write!(f, "{:02}:{:02}:{:02}.{:03}", h, m, s, ms)
+    }
+}
+
+/// base name of all outputs belonging to the sequence starting at the given frame
+pub fn sequence_name(frame: u32, start: Option<Timestamp>) -> String {
+    match start {
+        Some(ts) => format!("{:06}_{}", frame, ts.file_name()),
+        None => format!("{:06}", frame)
+    }
+}
+
+impl FromStr for Position {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(n) = s.parse::<u32>() {
+            return Ok(Position::Frame(n));
+        }
+
+        let invalid = || format!("expected a frame number or [[hh:]mm:]ss[.ms], got '{}'", s);
+
+        if s.split(':').count() > 3 {
+            return Err(invalid());
+        }
+
+        let mut seconds = 0.0;
+        for part in s.split(':') {
+            let v = part.parse::<f64>().map_err(|_| invalid())?;
+            if !v.is_finite() || v < 0.0 {
+                return Err(invalid());
+            }
+            seconds = seconds * 60.0 + v;
+        }
+
+        Ok(Position::Time(seconds))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Position;
+
+    #[test]
+    fn parse() {
+        assert_eq!("250".parse::<Position>(), Ok(Position::Frame(250)));
+        assert_eq!("12.5".parse::<Position>(), Ok(Position::Time(12.5)));
+        assert_eq!("01:30".parse::<Position>(), Ok(Position::Time(90.0)));
+        assert_eq!("1:02:03.250".parse::<Position>(), Ok(Position::Time(3723.25)));
+        assert!("1:2:3:4".parse::<Position>().is_err());
+        assert!("abc".parse::<Position>().is_err());
+        assert!("nan".parse::<Position>().is_err());
+        assert!("1:inf".parse::<Position>().is_err());
+        assert_eq!(Position::Time(2.0).frames(23.976), 48);
+    }
+
+    #[test]
+    fn timestamp_names() {
+        use super::{Timestamp, sequence_name};
+
+        let ts = Timestamp::new(3723250, (1, 1000));
+        assert_eq!(ts.to_string(), "01:02:03.250");
+        assert_eq!(sequence_name(42, Some(ts)), "000042_01h02m03s250");
+        assert_eq!(sequence_name(42, None), "000042");
+    }
+}
@@ -0,0 +1,199 @@
+use std::fs::File;
+use std::io::Read;
+use toml;
+
+/// Thresholds of the pan detection heuristics
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Profile {
+    /// change of the error fraction between consecutive frame pairs that indicates a scene change
+    pub scene_change_error_jump: f32,
+    /// 75th percentile of the block error histogram from which on a dissimilar motion counts as scene change
+    pub mismatch_error_q75: u8,
+    /// minimum spread between the 10th and 90th luma percentile, flatter frames end a run
+    pub min_luma_spread: u8,
+    /// seconds of motion required to open a pan
+    pub min_pan_duration: f32,
+    /// seconds of video buffered while looking for a pan
+    pub max_queue_duration: f32,
+    /// motion per frame up to which a frame counts as still, as fraction of the frame diagonal
+    pub min_motion: f32,
+    /// frames that have to move to open a pan, however long they are held
+    #[serde(default = "default_min_motion_frames")]
+    pub min_motion_frames: u32,
+    /// error fraction below which a full frame comparison is trusted as motion vector
+    pub max_vector_error: f32
+}
+
+/// Profile values converted to the frame rate and resolution of a particular stream
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Thresholds {
+    pub scene_change_error_jump: f32,
+    pub mismatch_error_q75: u8,
+    pub min_luma_spread: u8,
+    /// seconds, measured in video time so held frames count with their full duration
+    pub min_pan_duration: f64,
+    pub max_queue_duration: f64,
+    /// seconds per decoded frame, for frames without timestamps
+    pub frame_duration: f64,
+    /// vectors with both components up to this many pixels count as still
+    pub still_motion_px: isize,
+    pub min_motion_frames: u32,
+    pub max_vector_error: f32
+}
+
+const ANIME_24P : Profile = Profile {
+    scene_change_error_jump: 6.5,
+    mismatch_error_q75: 10,
+    min_luma_spread: 35,
+    min_pan_duration: 0.25,
+    max_queue_duration: 1.0,
+    min_motion: 0.0004,
+    min_motion_frames: 2,
+    max_vector_error: 5.0
+};
+
+// logs written before the setting existed
+fn default_min_motion_frames() -> u32 {
+    ANIME_24P.min_motion_frames
+}
+
+// other material is tuned with overrides of these values, see the README
+pub const PRESETS : [(&'static str, Profile); 1] = [
+    ("anime-24p", ANIME_24P)
+];
+
+impl Default for Profile {
+    fn default() -> Self {
+        ANIME_24P
+    }
+}
+
+impl Profile {
+    pub fn preset(name: &str) -> Option<Profile> {
+        PRESETS.iter().find(|&&(n, _)| n == name).map(|&(_, p)| p)
+    }
+
+    pub fn thresholds(&self, fps: f64, width: u32, height: u32) -> Thresholds {
+        let fps = if fps > 0.0 { fps } else { 24.0 };
+        let frame_duration = 1.0 / fps;
+        let diagonal = ((width as f64).powi(2) + (height as f64).powi(2)).sqrt();
+
+        let min_pan_duration = self.min_pan_duration as f64;
+
+        Thresholds {
+            scene_change_error_jump: self.scene_change_error_jump,
+            mismatch_error_q75: self.mismatch_error_q75,
+            min_luma_spread: self.min_luma_spread,
+            min_pan_duration,
+            // the queue has to be able to hold a run long enough to open a pan
+            max_queue_duration: (self.max_queue_duration as f64).max(min_pan_duration + 2.0 * frame_duration),
+            frame_duration,
+            still_motion_px: (self.min_motion as f64 * diagonal).floor() as isize,
+            min_motion_frames: self.min_motion_frames,
+            max_vector_error: self.max_vector_error
+        }
+    }
+
+    /// A preset name or the path of a TOML file.
+    /// The file may name a `preset` to start from, otherwise unspecified values are taken from anime-24p.
+    pub fn load(spec: &str) -> Result<Profile, String> {
+        if let Some(p) = Profile::preset(spec) {
+            return Ok(p);
+        }
+
+        let mut contents = String::new();
+        File::open(spec).and_then(|mut f| f.read_to_string(&mut contents))
+            .map_err(|e| format!("could not read profile {}: {}", spec, e))?;
+
+        Profile::parse(&contents).map_err(|e| format!("invalid profile {}: {}", spec, e))
+    }
+
+    fn parse(contents: &str) -> Result<Profile, String> {
+        let overrides : toml::Value = contents.parse().map_err(|e: toml::de::Error| e.to_string())?;
+        let overrides = match overrides {
+            toml::Value::Table(t) => t,
+            _ => return Err("expected a table".to_owned())
+        };
+
+        let base = match overrides.get("preset") {
+            Some(&toml::Value::String(ref name)) => Profile::preset(name).ok_or(format!("unknown preset {}", name))?,
+            Some(_) => return Err("preset must be a string".to_owned()),
+            None => Profile::default()
+        };
+
+        let mut merged = match toml::Value::try_from(base).map_err(|e| e.to_string())? {
+            toml::Value::Table(t) => t,
+            _ => unreachable!()
+        };
+
+        for (k, v) in overrides {
+            if k == "preset" {
+                continue;
+            }
+            if !merged.contains_key(&k) {
+                return Err(format!("unknown setting {}", k));
+            }
+            merged.insert(k, v);
+        }
+
+        let profile : Profile = toml::Value::Table(merged).try_into().map_err(|e: toml::de::Error| e.to_string())?;
+        profile.validate()?;
+        Ok(profile)
+    }
+
+    /// the detector needs time to pass and the error comparisons need a margin
+    fn validate(&self) -> Result<(), String> {
+        let positive = [
+            ("scene_change_error_jump", self.scene_change_error_jump),
+            ("min_pan_duration", self.min_pan_duration),
+            ("max_queue_duration", self.max_queue_duration),
+            ("max_vector_error", self.max_vector_error)
+        ];
+        for &(key, value) in &positive {
+            if !(value.is_finite() && value > 0.0) {
+                return Err(format!("{} must be positive, got {}", key, value));
+            }
+        }
+        if !(self.min_motion.is_finite() && self.min_motion >= 0.0) {
+            return Err(format!("min_motion must not be negative, got {}", self.min_motion));
+        }
+        if self.min_motion_frames == 0 {
+            return Err("min_motion_frames must be at least 1".to_owned());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Profile;
+
+    #[test]
+    fn overrides() {
+        let p = Profile::parse("preset = \"anime-24p\"\nmin_pan_duration = 0.5\n").unwrap();
+        assert_eq!(p.min_pan_duration, 0.5);
+        assert_eq!(p.max_queue_duration, Profile::preset("anime-24p").unwrap().max_queue_duration);
+
+        assert_eq!(Profile::parse("").unwrap(), Profile::default());
+        assert!(Profile::parse("min_pan_durationz = 4").is_err());
+        assert!(Profile::parse("preset = \"nope\"").is_err());
+        assert_eq!(Profile::parse("min_pan_duration = 0.0"), Err("min_pan_duration must be positive, got 0".to_owned()));
+        assert!(Profile::parse("max_queue_duration = -1.0").unwrap_err().contains("max_queue_duration"));
+        assert!(Profile::parse("min_motion_frames = 0").is_err());
+    }
+
+    #[test]
+    fn normalized() {
+        let p = Profile::default();
+
+        let sd = p.thresholds(24.0, 720, 480);
+        assert_eq!((sd.min_pan_duration, sd.max_queue_duration, sd.still_motion_px), (0.25, 1.0, 0));
+
+        let uhd = p.thresholds(60.0, 3840, 2160);
+        assert_eq!((uhd.frame_duration, uhd.still_motion_px), (1.0 / 60.0, 1));
+
+        let mut short_queue = p;
+        short_queue.max_queue_duration = 0.1;
+        assert_eq!(short_queue.thresholds(24.0, 720, 480).max_queue_duration, 0.25 + 2.0 / 24.0);
+    }
+}
@@ -0,0 +1,277 @@
+use std::path::Path;
+use ffmpeg;
+use ffmpeg::codec::threading;
+use ffmpeg::frame::Video;
+use interlace::Fields;
+use pipeline::MVFrame;
+use manifest::{Manifest, Sequence, Placement};
+use budget::Job;
+use stitchers::linear::LinStitcher;
+use stitchers::spill::SpillCache;
+use error::{self, Error};
+
+// composites sequences again from the alignment in the manifest. only the frames
+// of the selected sequences get decoded, the motion search is skipped entirely.
+// offsets edited in the manifest take precedence over the saved estimates.
+
+pub fn restitch(input: &Path, manifest: &Manifest, names: &[String], config: ::Config, job: &Job) -> Result<(), Error> {
+    let dir = config.naming.output_dir(input);
+
+    for name in names {
+        if !manifest.sequences.iter().any(|s| &s.name == name) {
+            return Err(format!("no sequence {} in the manifest", name).into());
+        }
+    }
+
+    let mut failed = 0;
+
+    for seq in manifest.sequences.iter().filter(|s| names.is_empty() || names.contains(&s.name)) {
+        if seq.frames.is_empty() {
+            eprintln!("{}: no saved alignment, skipping", seq.name);
+            continue;
+        }
+
+        match restitch_sequence(input, seq, &config, &dir, job) {
+            Ok(()) => println!("{}: restitched {} frames", seq.name, seq.frames.len()),
+            Err(e) => {
+                eprintln!("{}: {}", seq.name, e);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(format!("{} sequences could not be restitched", failed).into());
+    }
+
+    Ok(())
+}
+
+fn restitch_sequence(input: &Path, seq: &Sequence, config: &::Config, dir: &Path, job: &Job) -> Result<(), Error> {
+    let (key, keys) = wanted_frames(&seq.frames)?;
+
+    error::ffmpeg_path(input)?;
+    let mut ctx = ffmpeg::format::input(&input).map_err(|e| format!("{}: {}", input.display(), e))?;
+
+    let vid_idx;
+    let time_base;
+    let frame_rate;
+    let mut decoder;
+
+    {
+        let vstream = ctx.streams().best(ffmpeg::media::Type::Video).ok_or(format!("{}: no video stream", input.display()))?;
+        vid_idx = vstream.index();
+        time_base = vstream.time_base();
+        frame_rate = if vstream.avg_frame_rate().numerator() > 0 {
+            vstream.avg_frame_rate()
+        } else {
+            vstream.rate()
+        };
+        let mut d = vstream.codec().decoder();
+        d.set_threading(threading::Config {
+            kind: threading::Type::Frame,
+            count: 0,
+            safe: true,
+        });
+        decoder = d.video()?;
+    }
+
+    if !MVFrame::supported_format(decoder.format()) {
+        return Err(format!("pixel format {:?} currently not supported", decoder.format()).into());
+    }
+
+    let fps = frame_rate.numerator() as f64 / ::std::cmp::max(frame_rate.denominator(), 1) as f64;
+    let mut stitcher = LinStitcher::new();
+    stitcher.set_layers(config.layers as usize);
+    stitcher.set_layout(config.layout, config.tile_size);
+    stitcher.set_encoding(config.composite_format, config.composite_quality);
+    stitcher.set_timing((time_base.numerator(), time_base.denominator()), fps);
+    stitcher.set_trajectory(config.trajectory);
+    if let Some(clip) = config.stabilize {
+        stitcher.set_stabilized(clip, config.backdrop);
+    }
+    if let Some(limit) = config.stitch_memory {
+        stitcher.set_spill(SpillCache::new(&::std::env::temp_dir(), limit));
+    }
+
+    // frame indices are counted from the start of the stream, only timestamps survive a seek
+    if let (FrameKey::Pts, Some(start)) = (key, seq.start_time) {
+        unsafe {
+            use ffmpeg::ffi;
+            // stored times are stream timestamps, they already include the start offset
+            let ts = (start * ffi::AV_TIME_BASE as f64) as i64;
+            let res = ffi::avformat_seek_file(ctx.as_mut_ptr(), -1, ::std::i64::MIN, ts, ts, 0);
+            if res < 0 {
+                eprintln!("seek failed ({}), decoding from the start instead", ffmpeg::Error::from(res));
+            }
+        }
+    }
+
+    // auto could decide differently on the frames after the seek, and field matching changes the pts
+    let mut fields = Fields::new(seq.fields.unwrap_or(config.fields), &decoder, time_base, frame_rate);
+    let mut feed = Feed {
+        placements: &seq.frames,
+        key,
+        keys,
+        next: 0,
+        counter: 0,
+        last_pts: None,
+        sar: ffmpeg::Rational(seq.sar.0, seq.sar.1),
+        job
+    };
+
+    for (stream, packet) in ctx.packets() {
+        if stream.index() != vid_idx {
+            continue;
+        }
+
+        let mut frame = Video::empty();
+        match decoder.decode(&packet, &mut frame) {
+            Ok(true) => {
+                fields.push(frame, job);
+                feed.drain(&mut fields, &mut stitcher)?;
+                if feed.done() {
+                    break;
+                }
+            }
+            Ok(false) => {}
+            Err(e) => eprintln!("{:?}", e)
+        }
+    }
+
+    while !feed.done() {
+        let mut frame = Video::empty();
+        match decoder.decode(&ffmpeg::packet::Packet::empty(), &mut frame) {
+            Ok(true) => {
+                fields.push(frame, job);
+                feed.drain(&mut fields, &mut stitcher)?;
+            }
+            _ => break
+        }
+    }
+
+    if !feed.done() {
+        fields.flush();
+        feed.drain(&mut fields, &mut stitcher)?;
+    }
+
+    if !feed.done() {
+        return Err(format!("found {} of {} frames in {}", feed.next, feed.keys.len(), input.display()).into());
+    }
+
+    stitcher.set_period(seq.period);
+    stitcher.set_name(seq.name.clone());
+    stitcher.write_linear_stitch(config.optimize, dir).map(|_| ())
+}
+
+/// How decoded frames are matched to the placements of a sequence
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FrameKey {
+    Pts,
+    /// display frame index as numbered by the analysis, for streams without timestamps
+    Index
+}
+
+/// the key of every placement in stitching order. timestamps are used if all frames have one
+fn wanted_frames(placements: &[Placement]) -> Result<(FrameKey, Vec<i64>), Error> {
+    let key = if placements.iter().all(|p| p.pts.is_some()) { FrameKey::Pts } else { FrameKey::Index };
+    let keys : Vec<i64> = placements.iter().map(|p| match (key, p.pts) {
+        (FrameKey::Pts, Some(pts)) => pts,
+        _ => p.idx as i64
+    }).collect();
+
+    // frames are stitched as they are decoded. e.g. field changes can make two of them share a pts
+    for (pair, p) in keys.windows(2).zip(placements.iter().skip(1)) {
+        if pair[1] <= pair[0] {
+            return Err(format!("frame {} does not follow the previous one in the stream", p.idx).into());
+        }
+    }
+
+    Ok((key, keys))
+}
+
+/// hands the frames of a sequence to the stitcher as they come out of the field filter
+struct Feed<'a> {
+    placements: &'a [Placement],
+    key: FrameKey,
+    keys: Vec<i64>,
+    /// next placement to stitch
+    next: usize,
+    /// display frames seen so far, numbered like FrameFeed does
+    counter: i64,
+    last_pts: Option<i64>,
+    sar: ffmpeg::Rational,
+    job: &'a Job
+}
+
+impl<'a> Feed<'a> {
+    fn done(&self) -> bool {
+        self.next == self.keys.len()
+    }
+
+    fn drain(&mut self, fields: &mut Fields, stitcher: &mut LinStitcher) -> Result<(), Error> {
+        while let Some(frame) = fields.pop() {
+            // repeated timestamps are dropped before numbering, as during the analysis
+            if let (Some(pts), Some(last)) = (frame.pts(), self.last_pts) {
+                if pts <= last {
+                    continue;
+                }
+            }
+            self.last_pts = frame.pts().or(self.last_pts);
+            let idx = self.counter;
+            self.counter += 1;
+
+            if self.done() {
+                continue;
+            }
+
+            let key = match self.key {
+                FrameKey::Pts => match frame.pts() {
+                    Some(pts) => pts,
+                    None => continue
+                },
+                FrameKey::Index => idx
+            };
+
+            if key < self.keys[self.next] {
+                continue;
+            }
+
+            let placement = self.placements[self.next];
+            if key > self.keys[self.next] {
+                return Err(format!("frame {} was not decoded", placement.idx).into());
+            }
+
+            let memory = self.job.reserve_frame(&frame);
+            stitcher.add_frame(placement.idx, placement.pts, frame, Some(placement.estimate), self.sar, Some(memory))?;
+            stitcher.correct_offset(placement.offset_x, placement.offset_y);
+            self.next += 1;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{wanted_frames, FrameKey};
+    use manifest::Placement;
+    use motion::search::Estimate;
+
+    fn placement(idx: u32, pts: Option<i64>) -> Placement {
+        let estimate = Estimate {x: 0, y: 0, area: 0, error_sum: 0, error_area: 0, histogram: [0; 256]};
+        Placement {idx, pts, offset_x: 0, offset_y: 0, estimate}
+    }
+
+    #[test]
+    fn keys_fall_back_to_indices() {
+        let timed = [placement(10, Some(1001)), placement(11, Some(2002))];
+        assert_eq!(wanted_frames(&timed).unwrap(), (FrameKey::Pts, vec![1001, 2002]));
+
+        let untimed = [placement(10, Some(1001)), placement(11, None)];
+        assert_eq!(wanted_frames(&untimed).unwrap(), (FrameKey::Index, vec![10, 11]));
+
+        let shared = [placement(10, Some(1001)), placement(11, Some(1001))];
+        assert!(wanted_frames(&shared).is_err());
+    }
+}
@@ -0,0 +1,183 @@
+use std::cmp::min;
+use error::Error;
+
+// Deep Zoom tile pyramid. the full resolution image arrives in bands of rows, every level
+// only buffers the rows that don't make up a full row of tiles yet and hands each finished band,
+// halved, down to the next smaller level. so memory stays at a few rows of tiles per level.
+
+/// edge length of the tiles, what web viewers usually expect
+pub const TILE_SIZE : u32 = 256;
+
+pub struct Pyramid {
+    tile_size: u32,
+    /// full resolution first, down to a single pixel
+    levels: Vec<Level>
+}
+
+struct Level {
+    width: u32,
+    height: u32,
+    /// rows pushed so far
+    received: u32,
+    /// rows written as tiles
+    written: u32,
+    /// RGBA rows between the two
+    rows: Vec<u8>
+}
+
+impl Pyramid {
+    pub fn new(width: u32, height: u32, tile_size: u32) -> Pyramid {
+        let mut levels = vec![];
+        let (mut w, mut h) = (width, height);
+        loop {
+            levels.push(Level {width: w, height: h, received: 0, written: 0, rows: vec![]});
+            if w <= 1 && h <= 1 {
+                break;
+            }
+            w = (w + 1) / 2;
+            h = (h + 1) / 2;
+        }
+
+        Pyramid {tile_size, levels}
+    }
+
+    /// deep zoom level number of the full resolution image, the smallest one is 0
+    pub fn max_level(&self) -> u32 {
+        self.levels.len() as u32 - 1
+    }
+
+    /// adds the next rows of the full resolution image, RGBA without padding.
+    /// `tile` gets the finished tiles: level, column, row, width, height and pixels
+    pub fn push<F>(&mut self, rows: Vec<u8>, tile: &mut F) -> Result<(), Error>
+        where F: FnMut(u32, u32, u32, u32, u32, &[u8]) -> Result<(), Error> {
+        self.push_level(0, rows, tile)
+    }
+
+    fn push_level<F>(&mut self, idx: usize, rows: Vec<u8>, tile: &mut F) -> Result<(), Error>
+        where F: FnMut(u32, u32, u32, u32, u32, &[u8]) -> Result<(), Error> {
+        let tile_size = self.tile_size;
+        let number = self.max_level() - idx as u32;
+
+        {
+            let level = &mut self.levels[idx];
+            level.received += (rows.len() / (level.width as usize * 4)) as u32;
+            level.rows.extend(rows);
+        }
+
+        loop {
+            let (first, band) = match self.levels[idx].take_band(tile_size) {
+                Some(band) => band,
+                None => break
+            };
+            let width = self.levels[idx].width;
+            let height = (band.len() / (width as usize * 4)) as u32;
+
+            for col in 0 .. (width + tile_size - 1) / tile_size {
+                let x = col * tile_size;
+                let w = min(tile_size, width - x);
+                let pixels = cut(&band, width, x, w);
+                tile(number, col, first / tile_size, w, height, &pixels)?;
+            }
+
+            if idx + 1 < self.levels.len() {
+                let halved = halve(&band, width, height);
+                self.push_level(idx + 1, halved, tile)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Level {
+    /// the next full row of tiles, or the rest once all rows arrived. with the index of its first row
+    fn take_band(&mut self, tile_size: u32) -> Option<(u32, Vec<u8>)> {
+        let buffered = self.received - self.written;
+        if buffered == 0 || (buffered < tile_size && self.received < self.height) {
+            return None;
+        }
+
+        let rows = min(tile_size, buffered);
+        let band = self.rows.drain(.. (rows * self.width * 4) as usize).collect();
+        let first = self.written;
+        self.written += rows;
+        Some((first, band))
+    }
+}
+
+/// columns `x` to `x + w` of RGBA rows of `width` pixels
+fn cut(rows: &[u8], width: u32, x: u32, w: u32) -> Vec<u8> {
+    let stride = width as usize * 4;
+    let mut out = Vec::with_capacity(rows.len() / width as usize * w as usize);
+    for row in rows.chunks(stride) {
+        out.extend_from_slice(&row[x as usize * 4 .. (x + w) as usize * 4]);
+    }
+    out
+}
+
+/// 2x2 box filter, odd edges average the pixels that are there
+pub fn halve(rows: &[u8], width: u32, height: u32) -> Vec<u8> {
+    let (w, h) = (width as usize, height as usize);
+    let (hw, hh) = ((w + 1) / 2, (h + 1) / 2);
+    let mut out = vec![0u8; hw * hh * 4];
+
+    for y in 0..hh {
+        for x in 0..hw {
+            for c in 0..4 {
+                let mut sum = 0u32;
+                let mut n = 0;
+                for sy in y * 2 .. min(y * 2 + 2, h) {
+                    for sx in x * 2 .. min(x * 2 + 2, w) {
+                        sum += rows[(sy * w + sx) * 4 + c] as u32;
+                        n += 1;
+                    }
+                }
+                out[(y * hw + x) * 4 + c] = ((sum + n / 2) / n) as u8;
+            }
+        }
+    }
+
+    out
+}
+
+/// the .dzi descriptor, tiles go into `<name>_files/<level>/<column>_<row>.<format>` next to it
+pub fn descriptor(width: u32, height: u32, tile_size: u32, format: &str) -> String {
+    format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <Image xmlns=\"http://schemas.microsoft.com/deepzoom/2008\" TileSize=\"{}\" Overlap=\"0\" Format=\"{}\">\n\
+             \x20 <Size Width=\"{}\" Height=\"{}\"/>\n\
+             </Image>\n", tile_size, format, width, height)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Pyramid, halve};
+    use error::Error;
+
+    #[test]
+    fn pyramid() {
+        // one grey level per pixel, halved by averaging
+        assert_eq!(halve(&[10, 10, 10, 10, 30, 30, 30, 30, 50, 50, 50, 50], 3, 1), vec![20, 20, 20, 20, 50, 50, 50, 50]);
+
+        let mut pyramid = Pyramid::new(5, 3, 2);
+        assert_eq!(pyramid.max_level(), 3);
+
+        let mut tiles = vec![];
+        {
+            let mut collect = |level: u32, col: u32, row: u32, w: u32, h: u32, pixels: &[u8]| -> Result<(), Error> {
+                assert_eq!(pixels.len() as u32, w * h * 4);
+                tiles.push((level, col, row, w, h));
+                Ok(())
+            };
+            pyramid.push(vec![0; 5 * 2 * 4], &mut collect).unwrap();
+            pyramid.push(vec![0; 5 * 4], &mut collect).unwrap();
+        }
+
+        tiles.sort();
+        assert_eq!(tiles, vec![
+            (0, 0, 0, 1, 1),
+            (1, 0, 0, 2, 1),
+            (2, 0, 0, 2, 2), (2, 1, 0, 1, 2),
+            (3, 0, 0, 2, 2), (3, 0, 1, 2, 1), (3, 1, 0, 2, 2), (3, 1, 1, 2, 1), (3, 2, 0, 1, 2), (3, 2, 1, 1, 1)
+        ]);
+    }
+}
@@ -3,9 +3,23 @@ use ffmpeg::frame::Video;
 use ffmpeg::util::format::pixel::Pixel;
 use std::fmt;
 use motion::search::{self, Estimate};
+use motion::layers::{self, Layer};
+use manifest::Placement;
 use oxipng;
 use std::collections::HashSet;
 use ffmpeg;
+use ffmpeg::codec::threading;
+use error::{self, Error};
+use budget::{self, Reservation};
+use stitchers::spill::{SpillCache, Spilled};
+use stitchers::output::{Layout, Encoding, PngStream, TileIndex, Tile};
+use stitchers::dzi::{self, Pyramid};
+use clip::{Clip, ClipOut};
+use stitchers::trajectory::{Trajectory, Key};
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::BufWriter;
+use serde_json;
 
 
 // ideas/todo
@@ -23,144 +37,635 @@ use ffmpeg;
 // - reduce motion search cost by only diffing every Nth frame and filling the gaps when we detect a scene
 // - simplify run detection logic by operating on windows over buffers of frames
 
+/// how far a layer may move against the background between frames and still continue its track,
+/// as fraction of the frame diagonal
+const MAX_LAYER_DRIFT : f32 = 0.15;
 
 struct AlignedFrame {
-    avframe: Video,
+    idx: u32,
+    pixels: Pixels,
+    width: u32,
+    height: u32,
+    pts: Option<i64>,
     offset_x: isize,
     offset_y: isize,
     estimate: Estimate,
-    sar: ffmpeg::Rational
+    sar: ffmpeg::Rational,
+    layers: Vec<AlignedLayer>
+}
+
+/// the frame itself, moved to the spill cache once the stitcher exceeds its memory limit
+enum Pixels {
+    /// with its share of the memory budget, if it is accounted
+    Memory(Video, Option<Reservation>),
+    Spilled(Spilled)
+}
+
+/// a part of the frame moving independently of the background, e.g. a parallax foreground
+struct AlignedLayer {
+    /// the composite it belongs to, counted from 0 after the background
+    track: usize,
+    layer: Layer,
+    offset_x: isize,
+    offset_y: isize
 }
 
 
 impl AlignedFrame {
 
+    /// the layers of `frame` that move differently than `estimate`, placed relative to `other`.
+    /// each continues the track of the layer of `other` it lines up with, layers matching none
+    /// start a new track numbered from `tracks`
+    fn compute_layers(frame: &Video, estimate: &Estimate, other: &AlignedFrame, other_pixels: &Video, max_layers: usize, tracks: &mut usize) -> Result<Vec<AlignedLayer>, Error> {
+        use float_ord::FloatOrd;
+
+        let layers = layers::segment(frame, other_pixels, estimate, max_layers)?;
+        let diagonal = ((frame.width() as f32).powi(2) + (frame.height() as f32).powi(2)).sqrt();
+        let mut taken = vec![false; other.layers.len()];
+
+        Ok(layers.into_iter().map(|layer| {
+            // where the layer was in the reference frame, compared by position and motion
+            let matched = layer.mask.centroid().and_then(|(cx, cy)| {
+                let (cx, cy) = (cx + layer.estimate.x as f32, cy + layer.estimate.y as f32);
+                other.layers.iter().enumerate().filter(|&(i, _)| !taken[i]).filter_map(|(i, l)| {
+                    l.layer.mask.centroid().map(|(ox, oy)| {
+                        let moved = ((cx - ox).powi(2) + (cy - oy).powi(2)).sqrt();
+                        let motion = (((layer.estimate.x - l.layer.estimate.x).pow(2) + (layer.estimate.y - l.layer.estimate.y).pow(2)) as f32).sqrt();
+                        (i, moved, moved + motion)
+                    })
+                }).filter(|&(_, moved, _)| moved < diagonal * MAX_LAYER_DRIFT).min_by_key(|&(_, _, score)| FloatOrd(score))
+            });
+
+            let (track, x, y) = match matched {
+                Some((i, _, _)) => {
+                    taken[i] = true;
+                    let l = &other.layers[i];
+                    (l.track, l.offset_x, l.offset_y)
+                },
+                None => {
+                    *tracks += 1;
+                    (*tracks - 1, other.offset_x, other.offset_y)
+                }
+            };
+            AlignedLayer {track, offset_x: x + layer.estimate.x, offset_y: y + layer.estimate.y, layer}
+        }).collect())
+    }
 
-    fn compute_estimate(&mut self, other: &AlignedFrame, hint: Option<(isize, isize)> ) {
+    fn in_memory(&self) -> usize {
+        match self.pixels {
+            Pixels::Memory(ref frame, _) => budget::frame_bytes(frame),
+            Pixels::Spilled(_) => 0
+        }
+    }
 
-        let estimate = search::search(&self.avframe, &other.avframe, hint, 0);
-        self.estimate = estimate;
+    /// position of the frame on the canvas of `layer`, None if the frame doesn't contribute to it
+    fn layer_offset(&self, layer: usize) -> Option<(isize, isize)> {
+        if layer == 0 {
+            return Some((self.offset_x, self.offset_y));
+        }
+        self.layer(layer).map(|l| (l.offset_x, l.offset_y))
     }
 
-    fn set_estimate(&mut self, estimate: Estimate) {
-        self.estimate = estimate;
+    fn layer(&self, layer: usize) -> Option<&AlignedLayer> {
+        self.layers.iter().find(|l| l.track + 1 == layer)
     }
 
-    fn offset_from_estimate(&mut self, other: &AlignedFrame) {
-        self.offset_x = other.offset_x + self.estimate.x;
-        self.offset_y = other.offset_y + self.estimate.y;
+    /// whether the pixel at `x`,`y` belongs to `layer`
+    fn covers(&self, layer: usize, x: u32, y: u32) -> bool {
+        let (x, y) = (x as usize, y as usize);
+        if layer == 0 {
+            !self.layers.iter().any(|l| l.layer.mask.contains_px(x, y))
+        } else {
+            self.layer(layer).map(|l| l.layer.mask.contains_px(x, y)).unwrap_or(false)
+        }
     }
 }
 
 
 impl fmt::Debug for AlignedFrame {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}", (self.offset_x, self.offset_y, self.estimate))
+        write!(f, "{:?}", (self.offset_x, self.offset_y, self.estimate))?;
+        for l in &self.layers {
+            write!(f, " layer {}: {:?}", l.track, (l.offset_x, l.offset_y, l.layer.mask.count(), l.layer.estimate))?;
+        }
+        Ok(())
     }
 }
 
+/// which of the optional outputs were written
+pub struct Extras {
+    pub stabilized: bool,
+    pub trajectory: bool
+}
+
 pub struct LinStitcher {
-    start_frame: u32,
-    frames: Vec<AlignedFrame>
+    /// base name of the composites
+    name: String,
+    max_layers: usize,
+    /// independently moving layers found so far, each gets its own composite
+    tracks: usize,
+    period: Option<(isize, isize)>,
+    frames: Vec<AlignedFrame>,
+    /// None keeps all frames in memory
+    spill: Option<SpillCache>,
+    layout: Layout,
+    /// edge length of tiles and height of the bands of streamed PNGs
+    tile_size: u32,
+    encoding: Encoding,
+    quality: Option<u8>,
+    /// clip format of the stabilized sequence and whether the composite is shown around the frames
+    stabilized: Option<(Clip, bool)>,
+    /// write the camera move as CSV, JSON and After Effects script
+    trajectory: bool,
+    /// of the frame timestamps
+    time_base: (i32, i32),
+    fps: f64
 }
 
 impl LinStitcher {
     pub fn new() -> LinStitcher {
-        LinStitcher{start_frame: 0, frames: vec![]}
+        LinStitcher{name: String::new(), max_layers: 1, tracks: 0, period: None, frames: vec![], spill: None, layout: Layout::Whole, tile_size: 4096, encoding: Encoding::Png, quality: None, stabilized: None, trajectory: false, time_base: (24, 1000), fps: 24.0}
     }
 
-    pub fn set_start_frame(&mut self, frame_idx: u32) {
-        self.start_frame = frame_idx;
+    pub fn set_layout(&mut self, layout: Layout, tile_size: u32) {
+        self.layout = layout;
+        self.tile_size = ::std::cmp::max(tile_size, 1);
     }
 
-    pub fn add_frame(&mut self, frame: Video, motion: Option<Estimate>, sar: ffmpeg::Rational) {
-        let area = frame.height() * frame.width();
-        let mut new_frame = AlignedFrame{avframe: frame, offset_x: 0, offset_y: 0, estimate: Estimate::still(area), sar};
+    /// image format of composites and tiles. streamed composites are always PNG
+    pub fn set_encoding(&mut self, encoding: Encoding, quality: Option<u8>) {
+        self.encoding = encoding;
+        self.quality = quality;
+    }
 
-        if let Some(m) = motion {
-            new_frame.set_estimate(m);
-        }
+    /// time base of the pts passed to `add_frame` and the frame rate of the stream
+    pub fn set_timing(&mut self, time_base: (i32, i32), fps: f64) {
+        self.time_base = time_base;
+        self.fps = fps;
+    }
+
+    /// also writes the frames placed on the canvas as a video, see `write_stabilized`
+    pub fn set_stabilized(&mut self, clip: Clip, backdrop: bool) {
+        self.stabilized = Some((clip, backdrop));
+    }
+
+    pub fn set_trajectory(&mut self, trajectory: bool) {
+        self.trajectory = trajectory;
+    }
 
-        if let Some(frame) = self.frames.iter().rev()
-            .filter(|f| f.estimate.x != 0 || f.estimate.y != 0)
-            .chain(self.frames.iter().take(1))
-            .next() {
+    /// frames beyond the limit of the cache get moved to disk until they are composited
+    pub fn set_spill(&mut self, cache: SpillCache) {
+        self.spill = Some(cache);
+    }
+
+    pub fn set_name(&mut self, name: String) {
+        self.name = name;
+    }
+
+    /// composite up to `layers` independently moving layers separately. 1 = background only
+    pub fn set_layers(&mut self, layers: usize) {
+        self.max_layers = ::std::cmp::max(layers, 1);
+    }
+
+    /// `memory` is the share of the budget accounted to the frame, released once it is spilled or composited.
+    /// `pts` is the timestamp the manifest refers to the frame by
+    pub fn add_frame(&mut self, idx: u32, pts: Option<i64>, frame: Video, motion: Option<Estimate>, sar: ffmpeg::Rational, memory: Option<Reservation>) -> Result<(), Error> {
+        let area = frame.height() * frame.width();
+        let mut estimate = motion.unwrap_or(Estimate::still(area));
+        let mut offset = (0, 0);
+        let mut layers = vec![];
+        let mut tracks = self.tracks;
+
+        if let Some(i) = self.reference() {
+            let reference = &self.frames[i];
+            let pixels = self.pixels(reference)?;
             if motion.is_none() {
                 let hint = self.frames.iter().rev().map(|f| (f.estimate.x,f.estimate.y)).next();
-                new_frame.compute_estimate(&frame, hint);
+                estimate = search::search(&frame, &pixels, hint, 0);
+            }
+            offset = (reference.offset_x + estimate.x, reference.offset_y + estimate.y);
+            if self.max_layers > 1 {
+                layers = AlignedFrame::compute_layers(&frame, &estimate, reference, &pixels, self.max_layers - 1, &mut tracks)?;
             }
-            new_frame.offset_from_estimate(&frame);
         }
 
+        self.tracks = tracks;
+        self.frames.push(AlignedFrame {
+            idx,
+            width: frame.width(),
+            height: frame.height(),
+            pts,
+            pixels: Pixels::Memory(frame, memory),
+            offset_x: offset.0,
+            offset_y: offset.1,
+            estimate,
+            sar,
+            layers
+        });
 
-        self.frames.push(new_frame);
+        self.spill()
+    }
+
+    /// the frame the next one gets aligned to, the last one that moved
+    fn reference(&self) -> Option<usize> {
+        self.frames.iter().rposition(|f| f.estimate.x != 0 || f.estimate.y != 0)
+            .or(if self.frames.is_empty() { None } else { Some(0) })
+    }
+
+    fn pixels<'a>(&'a self, frame: &'a AlignedFrame) -> Result<Cow<'a, Video>, Error> {
+        match frame.pixels {
+            Pixels::Memory(ref pixels, _) => Ok(Cow::Borrowed(pixels)),
+            Pixels::Spilled(ref spilled) => {
+                let cache = self.spill.as_ref().ok_or("spilled frame without a cache")?;
+                Ok(Cow::Owned(cache.load(spilled)?))
+            }
+        }
+    }
+
+    /// moves frames to disk, oldest first, until the ones in memory fit into the limit again.
+    /// the first frame and the reference for the next one stay, period detection and alignment need them
+    fn spill(&mut self) -> Result<(), Error> {
+        let limit = match self.spill {
+            Some(ref cache) => cache.limit(),
+            None => return Ok(())
+        };
+
+        let keep = self.reference();
+        let mut in_memory : usize = self.frames.iter().map(|f| f.in_memory()).sum();
+
+        for i in 1..self.frames.len() {
+            if in_memory <= limit {
+                break;
+            }
+            if Some(i) == keep {
+                continue;
+            }
+
+            let spilled = match self.frames[i].pixels {
+                Pixels::Memory(ref pixels, _) => {
+                    in_memory -= budget::frame_bytes(pixels);
+                    self.spill.as_mut().unwrap().store(pixels)?
+                }
+                Pixels::Spilled(_) => continue
+            };
+            // drops the frame and its reservation
+            self.frames[i].pixels = Pixels::Spilled(spilled);
+        }
+
+        Ok(())
+    }
+
+    /// Looks for a cycling background, i.e. a frame that lines up with the first one again
+    /// after the pan moved on by at least a full frame, and the frame after it with the second one.
+    /// On success the frames after the repetition are dropped, the composite gets cropped to
+    /// a single period and the period is returned.
+    pub fn detect_period(&mut self) -> Option<(isize, isize)> {
+        use float_ord::FloatOrd;
+
+        // every candidate costs a full search, on spilled frames a read from disk too
+        const MAX_CANDIDATES: usize = 4;
+
+        if self.frames.len() < 4 {
+            return None;
+        }
+
+        let found = {
+            let first = &self.frames[0];
+            let second = &self.frames[1];
+            let w = first.width as isize;
+            let h = first.height as isize;
+
+            // what a match looks like for this particular pan
+            let mut errors = self.frames[1..].iter().map(|f| FloatOrd(f.estimate.error_fraction())).collect::<Vec<_>>();
+            errors.sort();
+            let typical = errors[errors.len() / 2].0;
+
+            // the period if `b` shows what `a` did. unreadable frames just don't count as a repetition
+            let repeats = |a: &AlignedFrame, b: &AlignedFrame| {
+                let (a_pixels, b_pixels) = match (self.pixels(a), self.pixels(b)) {
+                    (Ok(a_pixels), Ok(b_pixels)) => (a_pixels, b_pixels),
+                    _ => return None
+                };
+                let est = search::search(&b_pixels, &a_pixels, None, 0);
+                if est.error_fraction() > typical * 1.5 + 0.5 {
+                    return None;
+                }
+                Some((b.offset_x - a.offset_x - est.x, b.offset_y - a.offset_y - est.y))
+            };
+
+            self.frames.iter().enumerate().skip(2).filter(|&(_, f)| {
+                (f.offset_x - first.offset_x).abs() >= w || (f.offset_y - first.offset_y).abs() >= h
+            }).take(MAX_CANDIDATES).filter_map(|(i, f)| {
+                let period = match repeats(first, f) {
+                    Some(period) => period,
+                    None => return None
+                };
+                // low texture backgrounds match anywhere, a real cycle repeats the next frame as well
+                let confirmed = match self.frames.get(i + 1).and_then(|next| repeats(second, next)) {
+                    Some((px, py)) => (px - period.0).abs() <= 1 && (py - period.1).abs() <= 1,
+                    None => false
+                };
+                if confirmed { Some((i, period)) } else { None }
+            }).next()
+        };
+
+        if let Some((last, period)) = found {
+            self.frames.truncate(last + 1);
+            self.period = Some(period);
+            self.renumber_tracks();
+        }
+
+        self.period
+    }
+
+    /// drops the tracks of layers that no frame has any more
+    fn renumber_tracks(&mut self) {
+        let mut used = vec![false; self.tracks];
+        for l in self.frames.iter().flat_map(|f| f.layers.iter()) {
+            used[l.track] = true;
+        }
+        let renumbered: Vec<usize> = used.iter().scan(0, |next, &u| {
+            let track = *next;
+            if u {
+                *next += 1;
+            }
+            Some(track)
+        }).collect();
+
+        for l in self.frames.iter_mut().flat_map(|f| f.layers.iter_mut()) {
+            l.track = renumbered[l.track];
+        }
+        self.tracks = used.iter().filter(|&&u| u).count();
+    }
+
+    pub fn period(&self) -> Option<(isize, isize)> {
+        self.period
+    }
+
+    /// a previously detected period, the frames are expected to end after the repetition already
+    pub fn set_period(&mut self, period: Option<(isize, isize)>) {
+        self.period = period;
+    }
+
+    /// moves the most recently added frame to `x`,`y` on the canvas, e.g. for manual corrections.
+    /// frames added later get placed relative to the new position
+    pub fn correct_offset(&mut self, x: isize, y: isize) {
+        if let Some(f) = self.frames.last_mut() {
+            let (dx, dy) = (x - f.offset_x, y - f.offset_y);
+            f.offset_x = x;
+            f.offset_y = y;
+            for l in &mut f.layers {
+                l.offset_x += dx;
+                l.offset_y += dy;
+            }
+        }
+    }
+
+    pub fn sar(&self) -> Option<ffmpeg::Rational> {
+        self.frames.first().map(|f| f.sar)
+    }
+
+    pub fn placements(&self) -> Vec<Placement> {
+        self.frames.iter().map(|f| Placement {
+            idx: f.idx,
+            pts: f.pts,
+            offset_x: f.offset_x,
+            offset_y: f.offset_y,
+            estimate: f.estimate
+        }).collect()
+    }
+
+    /// index and pts of the last frame on the composite
+    pub fn last_frame(&self) -> Option<(u32, Option<i64>)> {
+        self.frames.last().map(|f| (f.idx, f.pts))
+    }
+
+    pub fn canvas_size(&self) -> (u32, u32) {
+        let dims = self.dims();
+        (dims.size.width as u32, dims.size.height as u32)
+    }
+
+    /// file names of the composites, the background first
+    /// tiled composites are named after their index
+    pub fn composite_names(&self) -> Vec<String> {
+        let name = &self.name;
+        let extension = match self.layout {
+            Layout::Tiles => "json",
+            Layout::Dzi => "dzi",
+            Layout::Stream => "png",
+            Layout::Whole => self.encoding.extension()
+        };
+        (0..self.layer_count()).map(|layer| {
+            if layer == 0 {
+                format!("{}_lin.{}", name, extension)
+            } else {
+                format!("{}_lin_layer{}.{}", name, layer, extension)
+            }
+        }).collect()
+    }
+
+    pub fn stabilized_name(&self) -> Option<String> {
+        self.stabilized.map(|(clip, _)| format!("{}_stab.{}", self.name, clip.extension()))
+    }
+
+    pub fn trajectory_names(&self) -> Vec<String> {
+        if !self.trajectory {
+            return vec![];
+        }
+        ["csv", "json", "jsx"].iter().map(|ext| format!("{}_cam.{}", self.name, ext)).collect()
+    }
+
+    /// positions of the frames on the background composite, in stitching order
+    pub fn trajectory(&self) -> Trajectory {
+        let (origin_x, origin_y) = self.layer_origin(0);
+        let (cw, ch) = self.layer_size(0);
+        let (dw, dh) = self.display_size(0);
+        let (sx, sy) = (dw as f64 / ::std::cmp::max(cw, 1) as f64, dh as f64 / ::std::cmp::max(ch, 1) as f64);
+        let first_pts = self.frames.first().and_then(|f| f.pts);
+        let (num, den) = self.time_base;
+        let sar = self.frames[0].sar;
+
+        let keys = self.frames.iter().map(|f| {
+            let (x, y) = (f.offset_x - origin_x, f.offset_y - origin_y);
+            Key {
+                frame: f.idx,
+                pts: f.pts,
+                time: f.pts.and_then(|p| first_pts.map(|first| (p - first) as f64 * num as f64 / den as f64)),
+                canvas_x: x,
+                canvas_y: y,
+                x: x as f64 * sx,
+                y: y as f64 * sy
+            }
+        }).collect();
+
+        Trajectory {
+            name: self.name.clone(),
+            composite: self.composite_names().into_iter().next().unwrap_or(String::new()),
+            sar: (sar.numerator(), sar.denominator()),
+            canvas_size: (cw, ch),
+            composite_size: (dw, dh),
+            frame_size: ((self.frames[0].width as f64 * sx).round() as u32, (self.frames[0].height as f64 * sy).round() as u32),
+            keys
+        }
     }
 
     pub fn expansion_ratio(&self) -> f32 {
-        let frame = &self.frames[0].avframe;
-        let frame_size = frame.width() * frame.height();
+        let frame = &self.frames[0];
+        let frame_size = frame.width * frame.height;
         let merged_size = self.dims();
         (merged_size.size.width * merged_size.size.height) as f32 / frame_size as f32
     }
 
     fn dims(&self) -> ::euclid::Rect<isize> {
-        let mut canvas_dims : Rect<_> = rect(0,0,0,0);
+        self.layer_dims(0)
+    }
+
+    fn layer_dims(&self, layer: usize) -> ::euclid::Rect<isize> {
+        let mut canvas_dims : Option<Rect<_>> = None;
 
         for fr in &self.frames {
-            let r = rect(fr.offset_x, fr.offset_y, fr.avframe.width() as isize, fr.avframe.height() as isize);
-            canvas_dims = canvas_dims.union(&r);
+            if let Some((x, y)) = fr.layer_offset(layer) {
+                let r = rect(x, y, fr.width as isize, fr.height as isize);
+                canvas_dims = Some(canvas_dims.map(|d| d.union(&r)).unwrap_or(r));
+            }
         }
 
-        canvas_dims
+        canvas_dims.unwrap_or(rect(0,0,0,0))
+    }
+
+    fn layer_count(&self) -> usize {
+        self.tracks + 1
     }
 
-    pub fn merge(mut self) -> Video {
-        let canvas_dims = self.dims();
+    /// size of the composite of `layer` before aspect correction. a cycling background is cut to a single period
+    fn layer_size(&self, layer: usize) -> (u32, u32) {
+        use std::cmp::min;
 
-        let mut canvas = Video::new(Pixel::RGBA, canvas_dims.size.width as u32, canvas_dims.size.height as u32);
-        let canvas_stride = canvas.stride(0) / 4;
+        let dims = self.layer_dims(layer);
+        let (w, h) = (dims.size.width as u32, dims.size.height as u32);
 
+        match (layer, self.period) {
+            (0, Some((px, py))) => (
+                if px != 0 { min(px.abs() as u32, w) } else { w },
+                if py != 0 { min(py.abs() as u32, h) } else { h }
+            ),
+            _ => (w, h)
+        }
+    }
+
+    /// top left corner of the composite of `layer` on the canvas. a single period of a cycling background
+    /// starts where the first frame and its repetition are
+    fn layer_origin(&self, layer: usize) -> (isize, isize) {
+        use std::cmp::{min, max};
+
+        let dims = self.layer_dims(layer);
+        let (w, h) = self.layer_size(layer);
+        let start = |offset: isize, period: isize, lo: isize, hi: isize, size: u32| {
+            if period == 0 {
+                lo
+            } else {
+                max(lo, min(offset + min(period, 0), hi - size as isize))
+            }
+        };
+
+        match (layer, self.period, self.frames.first()) {
+            (0, Some((px, py)), Some(first)) => (
+                start(first.offset_x, px, dims.min_x(), dims.max_x(), w),
+                start(first.offset_y, py, dims.min_y(), dims.max_y(), h)
+            ),
+            _ => (dims.min_x(), dims.min_y())
+        }
+    }
+
+    /// size of the composite of `layer` as it is written, i.e. with square pixels
+    fn display_size(&self, layer: usize) -> (u32, u32) {
+        let (w, h) = self.layer_size(layer);
         let sar = self.frames[0].sar;
-        let (w,h,f) = {
-            let frame = &self.frames[0].avframe;
+
+        if sar.numerator() > 0 && (sar.denominator() != 1 || sar.numerator() != 1) {
+            if sar.numerator() > sar.denominator() {
+                (w * sar.numerator() as u32 / sar.denominator() as u32, h)
+            } else {
+                (w, h * sar.denominator() as u32 / sar.numerator() as u32)
+            }
+        } else {
+            (w, h)
+        }
+    }
+
+    fn composite(&self, layer: usize) -> Result<Video, Error> {
+        let (w, h) = self.display_size(layer);
+        self.render(layer, 0, 0, w, h)
+    }
+
+    /// the area `x`,`y`,`w`,`h` of the aspect corrected composite of `layer`
+    fn render(&self, layer: usize, x: u32, y: u32, w: u32, h: u32) -> Result<Video, Error> {
+        let canvas = self.layer_size(layer);
+        let display = self.display_size(layer);
+
+        if canvas == display {
+            return self.paint(layer, x, y, w, h);
+        }
+
+        aspect_correct(|x, y, w, h| self.paint(layer, x, y, w, h), canvas, display, (x, y, w, h))
+    }
+
+    /// paints the area `x`,`y`,`w`,`h` of the canvas of `layer`, before aspect correction.
+    /// only the frames overlapping it get converted
+    fn paint(&self, layer: usize, x: u32, y: u32, w: u32, h: u32) -> Result<Video, Error> {
+        let (origin_x, origin_y) = self.layer_origin(layer);
+        self.paint_region(layer, rect(origin_x + x as isize, origin_y + y as isize, w as isize, h as isize))
+    }
+
+    /// paints `region` of the canvas of `layer`, in canvas coordinates
+    fn paint_region(&self, layer: usize, region: Rect<isize>) -> Result<Video, Error> {
+        let (w, h) = (region.size.width as u32, region.size.height as u32);
+        let mut canvas = Video::new(Pixel::RGBA, w, h);
+        let canvas_stride = canvas.stride(0) / 4;
+
+        let (fw,fh,f) = {
+            let frame = self.pixels(&self.frames[0])?;
             (frame.width(),frame.height(),frame.format())
         };
 
-        let slice_target_height = canvas_dims.size.height as usize / ::rayon::current_num_threads();
-        let slice_chunk_size = slice_target_height * canvas_stride;
-
         // TODO 16bit PNG support
-        use ffmpeg::software::scaling::{flag, Context};
+        use ffmpeg::software::scaling::Context;
         use ffmpeg::util::color::range::Range;
-        let mut flags = flag::ACCURATE_RND;
-        flags.insert(flag::ERROR_DIFFUSION);
-        flags.insert(flag::BICUBIC);
-        flags.insert(flag::FULL_CHR_H_INP);
-        flags.insert(flag::FULL_CHR_H_INT);
-
-        let mut conv = Context::get(f, w, h, Pixel::RGBA, w, h, flags).unwrap();
-        let mut intermediate = Video::new(Pixel::RGBA, w, h);
+
+        let mut conv = Context::get(f, fw, fh, Pixel::RGBA, fw, fh, scaling_flags())?;
+        let mut intermediate = Video::new(Pixel::RGBA, fw, fh);
         intermediate.set_color_range(Range::JPEG);
 
         {
             let data_out : &mut[(u8,u8,u8,u8)] = canvas.plane_mut(0);
 
-            for (_, fr) in self.frames.drain(..).enumerate().filter(|&(i,ref f)| i == 0 || f.estimate.x != 0 || f.estimate.y != 0) {
-                conv.run(&fr.avframe, &mut intermediate).unwrap();
+            let placed = self.frames.iter().enumerate().filter(|&(i, f)| {
+                layer > 0 || i == 0 || f.estimate.x != 0 || f.estimate.y != 0
+            }).filter_map(|(_, f)| f.layer_offset(layer).map(|offset| (f, offset)));
+
+            for (fr, (offset_x, offset_y)) in placed {
+                let visible = match rect(offset_x, offset_y, fr.width as isize, fr.height as isize).intersection(&region) {
+                    Some(r) => r,
+                    None => continue
+                };
+
+                conv.run(&*self.pixels(fr)?, &mut intermediate)?;
 
                 let data_in : &[(u8,u8,u8,u8)] = intermediate.plane(0);
 
-                let x = fr.estimate.x;
-                let y = fr.estimate.x;
                 let h = intermediate.height();
                 let w = intermediate.width();
                 let input_stride = intermediate.stride(0) as u32 / 4;
 
+                // the visible part in frame coordinates
+                let (vx0, vy0) = ((visible.min_x() - offset_x) as u32, (visible.min_y() - offset_y) as u32);
+                let (vx1, vy1) = ((visible.max_x() - offset_x) as u32, (visible.max_y() - offset_y) as u32);
+
                 const SEAM_WIDTH : u32 = 8;
 
-                let idx_out = (fr.offset_x - canvas_dims.min_x()) + (fr.offset_y - canvas_dims.min_y()) * canvas_stride as isize;
+                let idx_out = (offset_x - region.min_x()) + (offset_y - region.min_y()) * canvas_stride as isize;
 
-                for y in 0 .. h {
+                for y in vy0 .. vy1 {
                     let idx_out = idx_out + y as isize * canvas_stride as isize;
                     let idx_in = y * input_stride;
 
@@ -168,10 +673,14 @@ impl LinStitcher {
 
                     let vertical_edge_dist = min(y, h - y - 1);
 
-                    for x in 0 .. w {
+                    for x in vx0 .. vx1 {
                         let idx_out = (idx_out + x as isize) as usize;
                         let idx_in = (idx_in + x) as usize;
 
+                        if !fr.covers(layer, x, y) {
+                            continue;
+                        }
+
                         let edge_dist = min(min(x, w - x - 1), vertical_edge_dist);
                         if edge_dist < SEAM_WIDTH  {
                             let old = data_out[idx_out];
@@ -198,75 +707,411 @@ impl LinStitcher {
             }
         }
 
-        if sar.numerator() > 0 && (sar.denominator() != 1 || sar.numerator() != 1) {
-            let (w,h) = (canvas.width(),canvas.height());
-            let (dw, dh) = if sar.numerator() > sar.denominator() {
-                (w * sar.numerator() as u32 / sar.denominator() as u32,h)
-            } else {
-                (w, h * sar.denominator() as u32 / sar.numerator() as u32)
-            };
-            let mut conv = Context::get(Pixel::RGBA, w, h, Pixel::RGBA, dw , dh, flags).unwrap();
-            let mut aspect_corrected = Video::empty(); // ::new(Pixel::RGBA, display_w , display_h );
-            aspect_corrected.set_color_range(Range::JPEG);
-            conv.run(&canvas, &mut aspect_corrected).unwrap();
-            ::std::mem::replace(&mut canvas, aspect_corrected);
+        Ok(canvas)
+    }
+
+    /// fails if a composite could not be written. the stabilized clip and the trajectory come after them,
+    /// their failures are only reported
+    pub fn write_linear_stitch(self, optimize: bool, dir: &::std::path::Path) -> Result<Extras, Error> {
+        for (layer, name) in self.composite_names().into_iter().enumerate() {
+            let path = dir.join(name);
+
+            match self.layout {
+                Layout::Whole => {
+                    self.write_image(&self.composite(layer)?, &path)?;
+                    if optimize && self.encoding == Encoding::Png {
+                        optimize_png(&path)?;
+                    }
+                }
+                // optimizing would load the whole image again
+                Layout::Stream => self.write_stream(layer, &path)?,
+                Layout::Tiles => self.write_tiles(layer, &path, optimize)?,
+                Layout::Dzi => self.write_dzi(layer, &path, optimize)?
+            }
         }
 
-        return canvas
+        let mut extras = Extras {stabilized: self.stabilized.is_some(), trajectory: self.trajectory};
+
+        if let Some((clip, backdrop)) = self.stabilized {
+            if let Err(e) = self.write_stabilized(clip, backdrop, dir) {
+                eprintln!("{}: writing the stabilized clip failed: {}", self.name, e);
+                extras.stabilized = false;
+                if let Some(name) = self.stabilized_name() {
+                    let _ = ::std::fs::remove_file(dir.join(name));
+                }
+            }
+        }
+
+        if self.trajectory {
+            if let Err(e) = self.write_trajectory(dir) {
+                eprintln!("{}: writing the trajectory failed: {}", self.name, e);
+                extras.trajectory = false;
+            }
+        }
+
+        Ok(extras)
     }
 
-    pub fn write_linear_stitch(self, optimize: bool, dir: &::std::path::Path) {
-        let start = self.start_frame;
-        let path = dir.join(format!("{:06}_lin.png", start));
+    fn write_trajectory(&self, dir: &::std::path::Path) -> Result<(), Error> {
+        use std::io::Write;
 
-        {
-            let frame = self.merge();
-
-            let mut octx = ffmpeg::format::output(&path).unwrap();
-            let codec = ffmpeg::encoder::find_by_name("png").unwrap();
-            let mut encoder = octx.add_stream(codec).unwrap().codec().encoder().video().unwrap();
-            //output.set_time_base((24, 1000));
-            encoder.set_time_base((24, 1000));
-            encoder.set_width(frame.width());
-            encoder.set_height(frame.height());
-            encoder.set_format(Pixel::RGBA);
-            encoder.set_compression(Some(0));
-
-            let mut packet = ffmpeg::codec::packet::packet::Packet::empty();
-            let mut encoder = encoder.open_as(codec).unwrap();
-            match encoder.encode(&frame, &mut packet) {
-                Ok(true) => {}
-                Ok(false) => {
-                    encoder.flush(&mut packet).unwrap();
+        let trajectory = self.trajectory();
+        let contents = vec![trajectory.csv(), trajectory.json()?, trajectory.after_effects()];
+        for (name, content) in self.trajectory_names().iter().zip(contents) {
+            let path = dir.join(name);
+            let mut file = File::create(&path).map_err(Error::io(&path))?;
+            file.write_all(content.as_bytes()).map_err(Error::io(&path))?;
+        }
+        Ok(())
+    }
+
+    /// every frame placed on the background canvas at its offset, so the camera motion is gone and only
+    /// what moves on the background changes. the rest of the canvas shows the composite if `backdrop`,
+    /// otherwise it stays transparent. it spans the whole canvas, for a cycling background that is
+    /// everything up to the repetition rather than a single period.
+    /// holds two canvases, the base and the one being encoded, and the converted frame in the encoder
+    fn write_stabilized(&self, clip: Clip, backdrop: bool, dir: &::std::path::Path) -> Result<(), Error> {
+        use ffmpeg::software::scaling::Context;
+        use ffmpeg::util::color::range::Range;
+
+        let dims = self.dims();
+        let (cw, ch) = self.canvas_size();
+        // even, for codecs with subsampled chroma
+        let (w, h) = ((cw + 1) & !1, (ch + 1) & !1);
+
+        let mut base = Video::new(Pixel::RGBA, w, h);
+        for b in base.data_mut(0).iter_mut() {
+            *b = 0;
+        }
+        let stride = base.stride(0);
+
+        if backdrop {
+            // from the same origin as the frames, not the one of the cropped composite
+            let composite = self.paint_region(0, dims)?;
+            let row = cw as usize * 4;
+            for (src, dst) in composite.data(0).chunks(composite.stride(0)).zip(base.data_mut(0).chunks_mut(stride)).take(ch as usize) {
+                dst[..row].copy_from_slice(&src[..row]);
+            }
+        }
+
+        let (fw, fh, f) = {
+            let frame = self.pixels(&self.frames[0])?;
+            (frame.width(), frame.height(), frame.format())
+        };
+        let mut conv = Context::get(f, fw, fh, Pixel::RGBA, fw, fh, scaling_flags())?;
+        let mut intermediate = Video::new(Pixel::RGBA, fw, fh);
+        intermediate.set_color_range(Range::JPEG);
+
+        let mut out = ClipOut::open_threaded(dir, &format!("{}_stab", self.name), clip, &base, self.frames[0].sar,
+                                             Some(self.time_base), self.fps, threading::Type::Slice)?;
+
+        let mut canvas = base.clone();
+        let row = fw as usize * 4;
+
+        for fr in &self.frames {
+            conv.run(&*self.pixels(fr)?, &mut intermediate)?;
+
+            let (x, y) = ((fr.offset_x - dims.min_x()) as usize, (fr.offset_y - dims.min_y()) as usize);
+            {
+                let src_rows = intermediate.data(0).chunks(intermediate.stride(0));
+                let dst_rows = canvas.data_mut(0).chunks_mut(stride).skip(y);
+                for (src, dst) in src_rows.zip(dst_rows).take(fh as usize) {
+                    dst[x * 4 .. x * 4 + row].copy_from_slice(&src[..row]);
                 }
-                Err(e) => eprintln!("{:?}", e)
             }
 
-            octx.write_header().unwrap();
-            packet.write(&mut octx).unwrap();
-            octx.write_trailer().unwrap();
+            // the encoder got a converted copy, the canvas is free again
+            out.encode(&canvas, fr.pts)?;
+
+            let src_rows = base.data(0).chunks(stride).skip(y);
+            let dst_rows = canvas.data_mut(0).chunks_mut(stride).skip(y);
+            for (src, dst) in src_rows.zip(dst_rows).take(fh as usize) {
+                dst[x * 4 .. x * 4 + row].copy_from_slice(&src[x * 4 .. x * 4 + row]);
+            }
+        }
+
+        out.finish()
+    }
+
+    /// renders the composite in bands of `tile_size` rows and encodes them as they are done
+    fn write_stream(&self, layer: usize, path: &::std::path::Path) -> Result<(), Error> {
+        let (w, h) = self.display_size(layer);
+        let file = File::create(path).map_err(Error::io(path))?;
+        let mut png = PngStream::new(BufWriter::new(file), w, h).map_err(Error::io(path))?;
+
+        let mut y = 0;
+        while y < h {
+            let rows = ::std::cmp::min(self.tile_size, h - y);
+            let band = self.render(layer, 0, y, w, rows)?;
+            let stride = band.stride(0);
+            let data = band.data(0);
+            for row in 0..rows as usize {
+                png.write_row(&data[row * stride .. row * stride + w as usize * 4]).map_err(Error::io(path))?;
+            }
+            y += rows;
+        }
+
+        png.finish().map_err(Error::io(path))?;
+        Ok(())
+    }
+
+    /// writes tiles of `tile_size` into a directory named like the index, without its extension
+    fn write_tiles(&self, layer: usize, index_path: &::std::path::Path, optimize: bool) -> Result<(), Error> {
+        use std::cmp::min;
+
+        let (w, h) = self.display_size(layer);
+        let size = self.tile_size;
+        let dir = index_path.with_extension("");
+        let dir_name = dir.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or(String::new());
+        ::std::fs::create_dir_all(&dir).map_err(Error::io(&dir))?;
+
+        let mut index = TileIndex {width: w, height: h, tile_size: size, tiles: vec![]};
+
+        for row in 0 .. (h + size - 1) / size {
+            for col in 0 .. (w + size - 1) / size {
+                let (x, y) = (col * size, row * size);
+                let (tw, th) = (min(size, w - x), min(size, h - y));
+                let file = format!("{}_{}.{}", col, row, self.encoding.extension());
+                let path = dir.join(&file);
+
+                self.write_image(&self.render(layer, x, y, tw, th)?, &path)?;
+                if optimize && self.encoding == Encoding::Png {
+                    optimize_png(&path)?;
+                }
+
+                index.tiles.push(Tile {file: format!("{}/{}", dir_name, file), x, y, width: tw, height: th});
+            }
+        }
+
+        let file = File::create(index_path).map_err(Error::io(index_path))?;
+        serde_json::to_writer_pretty(BufWriter::new(file), &index).map_err(|e| format!("{}: {}", index_path.display(), e))?;
+        Ok(())
+    }
+
+    /// deep zoom pyramid, built from bands of the full resolution composite
+    fn write_dzi(&self, layer: usize, descriptor: &::std::path::Path, optimize: bool) -> Result<(), Error> {
+        use std::io::Write;
+
+        let (w, h) = self.display_size(layer);
+        if w == 0 || h == 0 {
+            return Err(format!("{}: empty composite", descriptor.display()).into());
+        }
+
+        let stem = descriptor.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or(String::new());
+        let files = descriptor.with_file_name(format!("{}_files", stem));
+
+        let mut pyramid = Pyramid::new(w, h, dzi::TILE_SIZE);
+
+        let mut write_tile = |level: u32, col: u32, row: u32, tw: u32, th: u32, pixels: &[u8]| -> Result<(), Error> {
+            let dir = files.join(level.to_string());
+            if col == 0 && row == 0 {
+                ::std::fs::create_dir_all(&dir).map_err(Error::io(&dir))?;
+            }
+
+            let mut tile = Video::new(Pixel::RGBA, tw, th);
+            let stride = tile.stride(0);
+            for (src, dst) in pixels.chunks(tw as usize * 4).zip(tile.data_mut(0).chunks_mut(stride)) {
+                dst[..src.len()].copy_from_slice(src);
+            }
+
+            let path = dir.join(format!("{}_{}.{}", col, row, self.encoding.extension()));
+            self.write_image(&tile, &path)?;
+            if optimize && self.encoding == Encoding::Png {
+                optimize_png(&path)?;
+            }
+            Ok(())
+        };
+
+        // rows of a few tiles at a time, a multiple of the tile size keeps the pyramid buffers small
+        let band_rows = ::std::cmp::max(self.tile_size / dzi::TILE_SIZE, 1) * dzi::TILE_SIZE;
+        let mut y = 0;
+        while y < h {
+            let rows = ::std::cmp::min(band_rows, h - y);
+            let band = self.render(layer, 0, y, w, rows)?;
+            let stride = band.stride(0);
+            let mut packed = Vec::with_capacity((w * rows * 4) as usize);
+            for row in band.data(0).chunks(stride).take(rows as usize) {
+                packed.extend_from_slice(&row[.. w as usize * 4]);
+            }
+            pyramid.push(packed, &mut write_tile)?;
+            y += rows;
+        }
+
+        let mut file = File::create(descriptor).map_err(Error::io(descriptor))?;
+        file.write_all(dzi::descriptor(w, h, dzi::TILE_SIZE, self.encoding.extension()).as_bytes()).map_err(Error::io(descriptor))?;
+        Ok(())
+    }
+
+    /// a single RGBA image in the configured encoding
+    fn write_image(&self, frame: &Video, path: &::std::path::Path) -> Result<(), Error> {
+        use ffmpeg::software::scaling::Context;
+
+        let encoding = self.encoding;
+        let mut octx = ffmpeg::format::output(&error::ffmpeg_path(path)?)?;
+        let codec = ffmpeg::encoder::find_by_name(encoding.codec()).ok_or(format!("no {} encoder", encoding.codec()))?;
+        let mut encoder = octx.add_stream(codec)?.codec().encoder().video()?;
+        encoder.set_time_base((24, 1000));
+        encoder.set_width(frame.width());
+        encoder.set_height(frame.height());
+        encoder.set_format(encoding.pixel_format());
+        let options = encoding.configure(&mut encoder, self.quality);
+
+        let converted;
+        let frame = if encoding.pixel_format() != frame.format() {
+            let mut conv = Context::get(frame.format(), frame.width(), frame.height(),
+                                        encoding.pixel_format(), frame.width(), frame.height(), scaling_flags())?;
+            let mut out = Video::new(encoding.pixel_format(), frame.width(), frame.height());
+            conv.run(frame, &mut out)?;
+            converted = out;
+            &converted
+        } else {
+            frame
+        };
+
+        let mut packet = ffmpeg::codec::packet::packet::Packet::empty();
+        let mut encoder = encoder.open_as_with(codec, options)?;
+        if !encoder.encode(frame, &mut packet)? {
+            encoder.flush(&mut packet)?;
+        }
+
+        octx.write_header()?;
+        packet.write(&mut octx)?;
+        octx.write_trailer()?;
+        Ok(())
+    }
+
+}
+
+fn scaling_flags() -> ::ffmpeg::software::scaling::flag::Flags {
+    use ffmpeg::software::scaling::flag;
+
+    let mut flags = flag::ACCURATE_RND;
+    flags.insert(flag::ERROR_DIFFUSION);
+    flags.insert(flag::BICUBIC);
+    flags.insert(flag::FULL_CHR_H_INP);
+    flags.insert(flag::FULL_CHR_H_INT);
+    flags
+}
+
+/// bicubic taps of display pixel `i` when `canvas` pixels are stretched to `display`: the source pixels,
+/// clamped to the canvas, and their weights. the positions are exact so that any area of the
+/// composite comes out the same as when the whole canvas is scaled at once
+fn taps(i: u32, display: u32, canvas: u32) -> ([u32; 4], [f32; 4]) {
+    use std::cmp::{min, max};
+
+    // catmull-rom
+    let cubic = |d: f32| {
+        let d = d.abs();
+        if d < 1.0 {
+            1.5 * d * d * d - 2.5 * d * d + 1.0
+        } else if d < 2.0 {
+            -0.5 * d * d * d + 2.5 * d * d - 4.0 * d + 2.0
+        } else {
+            0.0
         }
+    };
+
+    let pos = (i as f64 + 0.5) * canvas as f64 / display as f64 - 0.5;
+    let base = pos.floor();
+    let t = (pos - base) as f32;
+
+    let mut idx = [0; 4];
+    let mut weights = [0.0; 4];
+    for k in 0..4 {
+        idx[k] = max(0, min(base as i64 - 1 + k as i64, canvas as i64 - 1)) as u32;
+        weights[k] = cubic(k as f32 - 1.0 - t);
+    }
+    (idx, weights)
+}
+
+/// scales the area `x`,`y`,`w`,`h` of a composite of `display` size out of a canvas of `canvas` size.
+/// `paint` is asked for the part of the canvas the area needs, including the neighbours of its edges.
+/// besides it and the result only four horizontally filtered rows are held
+fn aspect_correct<F>(paint: F, canvas: (u32, u32), display: (u32, u32), area: (u32, u32, u32, u32)) -> Result<Video, Error>
+    where F: FnOnce(u32, u32, u32, u32) -> Result<Video, Error>
+{
+    use ffmpeg::util::color::range::Range;
+
+    let (x, y, w, h) = area;
+    let cols: Vec<_> = (x..x + w).map(|i| taps(i, display.0, canvas.0)).collect();
+    let rows: Vec<_> = (y..y + h).map(|j| taps(j, display.1, canvas.1)).collect();
+    let span = |t: &[([u32; 4], [f32; 4])]| (t.first().map(|t| t.0[0]).unwrap_or(0), t.last().map(|t| t.0[3]).unwrap_or(0));
+    let (x0, x1) = span(&cols);
+    let (y0, y1) = span(&rows);
+
+    let src = paint(x0, y0, x1 - x0 + 1, y1 - y0 + 1)?;
+    let src_stride = src.stride(0) / 4;
+    let data_in: &[(u8, u8, u8, u8)] = src.plane(0);
 
-        if optimize {
-            let mut options = oxipng::Options::default();
-            let mut zm = HashSet::new();
-            zm.insert(9);
-            options.memory = zm;
-            let mut zf = HashSet::new();
-            zf.insert(5);
-            options.backup = false;
-            options.filter = zf;
-            options.verbosity = None;
-            options.out_file = path.clone();
+    let filter_row = |row: u32| {
+        let line = &data_in[(row - y0) as usize * src_stride..];
+        cols.iter().map(|&(ref idx, ref weights)| {
+            let mut acc = [0f32; 4];
+            for k in 0..4 {
+                let p = line[(idx[k] - x0) as usize];
+                acc[0] += weights[k] * p.0 as f32;
+                acc[1] += weights[k] * p.1 as f32;
+                acc[2] += weights[k] * p.2 as f32;
+                acc[3] += weights[k] * p.3 as f32;
+            }
+            acc
+        }).collect::<Vec<_>>()
+    };
+
+    let mut out = Video::new(Pixel::RGBA, w, h);
+    out.set_color_range(Range::JPEG);
+    let out_stride = out.stride(0) / 4;
+    {
+        let data_out: &mut [(u8, u8, u8, u8)] = out.plane_mut(0);
+        let quantize = |v: f32| v.round().max(0.0).min(255.0) as u8;
+        // horizontally filtered source rows. the taps only move down, so at most four are kept
+        let mut horizontal: Vec<(u32, Vec<[f32; 4]>)> = Vec::with_capacity(4);
+
+        for (r, &(ref idx, ref weights)) in rows.iter().enumerate() {
+            horizontal.retain(|&(row, _)| row >= idx[0]);
+            for &row in idx {
+                if !horizontal.iter().any(|&(h, _)| h == row) {
+                    horizontal.push((row, filter_row(row)));
+                }
+            }
+            let taps: Vec<&[[f32; 4]]> = idx.iter().map(|&row| {
+                &horizontal.iter().find(|&&(h, _)| h == row).unwrap().1[..]
+            }).collect();
 
-            oxipng::optimize(&path, &options).unwrap();
+            for c in 0..w as usize {
+                let mut acc = [0f32; 4];
+                for k in 0..4 {
+                    let p = taps[k][c];
+                    for ch in 0..4 {
+                        acc[ch] += weights[k] * p[ch];
+                    }
+                }
+                data_out[r * out_stride + c] = (quantize(acc[0]), quantize(acc[1]), quantize(acc[2]), quantize(acc[3]));
+            }
         }
     }
+    Ok(out)
+}
 
+fn optimize_png(path: &::std::path::Path) -> Result<(), Error> {
+    let mut options = oxipng::Options::default();
+    let mut zm = HashSet::new();
+    zm.insert(9);
+    options.memory = zm;
+    let mut zf = HashSet::new();
+    zf.insert(5);
+    options.backup = false;
+    options.filter = zf;
+    options.verbosity = None;
+    options.out_file = path.to_owned();
+
+    oxipng::optimize(path, &options).map_err(|e| format!("{}: optimizing failed: {}", path.display(), e))?;
+    Ok(())
 }
 
 
 
+
 impl fmt::Debug for LinStitcher {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         for frame in &self.frames {
@@ -274,4 +1119,50 @@ impl fmt::Debug for LinStitcher {
         }
         Ok(())
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+    use ffmpeg::frame::Video;
+    use ffmpeg::util::format::pixel::Pixel;
+    use super::aspect_correct;
+
+    #[test]
+    fn banded_matches_whole() {
+        // SAR 8:9 stretches the height
+        let (canvas, display) = ((40, 24), (40, 27));
+        let paint = |x: u32, y: u32, w: u32, h: u32| {
+            assert!(x + w <= canvas.0 && y + h <= canvas.1);
+            let mut v = Video::new(Pixel::RGBA, w, h);
+            let stride = v.stride(0) / 4;
+            {
+                let data: &mut [(u8, u8, u8, u8)] = v.plane_mut(0);
+                for j in 0..h {
+                    for i in 0..w {
+                        let (cx, cy) = (x + i, y + j);
+                        data[j as usize * stride + i as usize] = ((cx * 37 + cy * 11) as u8, (cx * cy) as u8, (cy * 29) as u8, 255);
+                    }
+                }
+            }
+            Ok::<_, ::error::Error>(v)
+        };
+        let pixel = |v: &Video, x: u32, y: u32| {
+            let data: &[(u8, u8, u8, u8)] = v.plane(0);
+            data[y as usize * v.stride(0) / 4 + x as usize]
+        };
+
+        let whole = aspect_correct(&paint, canvas, display, (0, 0, display.0, display.1)).unwrap();
+        for &(tw, th) in &[(display.0, 5), (16, 10)] {
+            for ty in (0..display.1).filter(|y| y % th == 0) {
+                for tx in (0..display.0).filter(|x| x % tw == 0) {
+                    let (w, h) = (::std::cmp::min(tw, display.0 - tx), ::std::cmp::min(th, display.1 - ty));
+                    let tile = aspect_correct(&paint, canvas, display, (tx, ty, w, h)).unwrap();
+                    for y in 0..h {
+                        for x in 0..w {
+                            assert_eq!(pixel(&tile, x, y), pixel(&whole, tx + x, ty + y), "tile {},{} pixel {},{}", tx, ty, x, y);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
@@ -1 +1,5 @@
-pub mod linear;
\ No newline at end of file
+pub mod linear;
+pub mod spill;
+pub mod output;
+pub mod dzi;
+pub mod trajectory;
@@ -0,0 +1,266 @@
+use std::io::{self, Write};
+use flate2;
+use flate2::write::ZlibEncoder;
+use std::ffi::CString;
+use ffmpeg;
+use ffmpeg::util::format::pixel::Pixel;
+
+// how composites are written: one PNG, one PNG encoded in horizontal bands, a set of tiles with an index
+// or a deep zoom pyramid for web viewers
+arg_enum!{
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    pub enum Layout {
+        Whole, Stream, Tiles, Dzi
+    }
+}
+
+// image format of composites and their tiles. webp is lossless unless a quality is given,
+// avif needs an ffmpeg built with libaom and recent enough to mux avif
+arg_enum!{
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    pub enum Encoding {
+        Png, Jpg, Webp, Tiff, Avif
+    }
+}
+
+impl Encoding {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Encoding::Png => "png",
+            Encoding::Jpg => "jpg",
+            Encoding::Webp => "webp",
+            Encoding::Tiff => "tiff",
+            Encoding::Avif => "avif"
+        }
+    }
+
+    pub fn codec(self) -> &'static str {
+        match self {
+            Encoding::Png => "png",
+            Encoding::Jpg => "mjpeg",
+            Encoding::Webp => "libwebp",
+            Encoding::Tiff => "tiff",
+            Encoding::Avif => "libaom-av1"
+        }
+    }
+
+    /// composites are rendered as RGBA, everything else gets converted first
+    pub fn pixel_format(self) -> Pixel {
+        match self {
+            Encoding::Png | Encoding::Tiff => Pixel::RGBA,
+            Encoding::Jpg => Pixel::YUVJ420P,
+            Encoding::Webp => Pixel::BGRA,
+            Encoding::Avif => Pixel::YUV420P
+        }
+    }
+
+    /// the encoder, and a muxer for the extension. avif files need a much newer ffmpeg than libaom does
+    pub fn check_support(self) -> Result<(), String> {
+        if ffmpeg::encoder::find_by_name(self.codec()).is_none() {
+            return Err(format!("the linked ffmpeg has no {} encoder", self.codec()));
+        }
+
+        let file = CString::new(format!("composite.{}", self.extension())).unwrap();
+        let muxer = unsafe { ffmpeg::ffi::av_guess_format(::std::ptr::null(), file.as_ptr(), ::std::ptr::null()) };
+        if muxer.is_null() {
+            return Err(format!("the linked ffmpeg can't write .{} files", self.extension()));
+        }
+        Ok(())
+    }
+
+    /// fastest PNG and lossless WebP without a `quality`
+    pub fn configure(self, encoder: &mut ffmpeg::encoder::video::Video, quality: Option<u8>) -> ffmpeg::Dictionary<'static> {
+        let mut options = configure_image(self.codec(), encoder, quality);
+        match self {
+            Encoding::Png => encoder.set_compression(Some(0)),
+            Encoding::Avif => {
+                // constant quality, 0 is best. a bitrate would make it constrained quality
+                encoder.set_bit_rate(0);
+                let crf = quality.map(|q| 63 - q as u32 * 63 / 100).unwrap_or(20);
+                options.set("crf", &crf.to_string());
+                options.set("still-picture", "1");
+            },
+            Encoding::Jpg | Encoding::Webp | Encoding::Tiff => {}
+        }
+        options
+    }
+}
+
+/// the quality settings of the image encoders used for both single frames and composites.
+/// `quality` 1-100, None for near-lossless JPEG and lossless WebP
+pub fn configure_image(codec: &str, encoder: &mut ffmpeg::encoder::video::Video, quality: Option<u8>) -> ffmpeg::Dictionary<'static> {
+    let mut options = ffmpeg::Dictionary::new();
+    match codec {
+        "mjpeg" => {
+            let (qmin, qmax) = jpeg_quantizer(quality);
+            encoder.set_global_quality(qmin);
+            encoder.set_qmin(qmin);
+            encoder.set_qmax(qmax);
+        },
+        "libwebp" => {
+            match quality {
+                Some(q) => options.set("quality", &q.to_string()),
+                None => options.set("lossless", "1")
+            }
+        },
+        _ => {}
+    }
+    options
+}
+
+/// mjpeg quantizer scale range for a quality of 1-100, 1 is best
+fn jpeg_quantizer(quality: Option<u8>) -> (i32, i32) {
+    match quality {
+        Some(q) => {
+            let scale = 2 + (100 - q as i32) * 29 / 100;
+            (scale, scale)
+        },
+        None => (1, 3)
+    }
+}
+
+/// Describes a composite written as tiles, saved next to the tile directory
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TileIndex {
+    pub width: u32,
+    pub height: u32,
+    pub tile_size: u32,
+    pub tiles: Vec<Tile>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Tile {
+    /// relative to the index
+    pub file: String,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32
+}
+
+const SIGNATURE : [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+const IDAT_SIZE : usize = 1 << 16;
+
+/// RGBA PNG written row by row, only the zlib state and the current IDAT chunk are kept in memory
+pub struct PngStream<W: Write> {
+    zlib: ZlibEncoder<Chunks<W>>,
+    row_bytes: usize
+}
+
+/// cuts the compressed stream into IDAT chunks
+struct Chunks<W: Write> {
+    out: W,
+    buf: Vec<u8>,
+    crc_table: [u32; 256]
+}
+
+impl<W: Write> PngStream<W> {
+    pub fn new(mut out: W, width: u32, height: u32) -> io::Result<Self> {
+        let crc_table = crc_table();
+
+        out.write_all(&SIGNATURE)?;
+        let mut header = vec![];
+        header.extend_from_slice(&be32(width));
+        header.extend_from_slice(&be32(height));
+        // 8 bit RGBA, deflate, no interlacing
+        header.extend_from_slice(&[8, 6, 0, 0, 0]);
+        write_chunk(&mut out, &crc_table, b"IHDR", &header)?;
+
+        let chunks = Chunks {out, buf: Vec::with_capacity(IDAT_SIZE), crc_table};
+        Ok(PngStream {zlib: ZlibEncoder::new(chunks, flate2::Compression::Fast), row_bytes: width as usize * 4})
+    }
+
+    pub fn write_row(&mut self, rgba: &[u8]) -> io::Result<()> {
+        assert_eq!(rgba.len(), self.row_bytes);
+        // filter type none
+        self.zlib.write_all(&[0])?;
+        self.zlib.write_all(rgba)
+    }
+
+    pub fn finish(self) -> io::Result<W> {
+        let mut chunks = self.zlib.finish()?;
+        chunks.write_idat()?;
+        write_chunk(&mut chunks.out, &chunks.crc_table, b"IEND", &[])?;
+        chunks.out.flush()?;
+        Ok(chunks.out)
+    }
+}
+
+impl<W: Write> Chunks<W> {
+    fn write_idat(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            write_chunk(&mut self.out, &self.crc_table, b"IDAT", &self.buf)?;
+            self.buf.clear();
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for Chunks<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= IDAT_SIZE {
+            self.write_idat()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+fn write_chunk<W: Write>(out: &mut W, crc_table: &[u32; 256], kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&be32(data.len() as u32))?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    out.write_all(&be32(crc32(crc_table, &[kind, data])))
+}
+
+fn be32(v: u32) -> [u8; 4] {
+    [(v >> 24) as u8, (v >> 16) as u8, (v >> 8) as u8, v as u8]
+}
+
+fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    for n in 0..256 {
+        let mut c = n as u32;
+        for _ in 0..8 {
+            c = if c & 1 != 0 { 0xedb88320 ^ (c >> 1) } else { c >> 1 };
+        }
+        table[n] = c;
+    }
+    table
+}
+
+fn crc32(table: &[u32; 256], parts: &[&[u8]]) -> u32 {
+    let mut crc = !0u32;
+    for part in parts {
+        for &b in part.iter() {
+            crc = table[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod test {
+    use super::{crc_table, crc32, PngStream};
+
+    #[test]
+    fn png_chunks() {
+        let table = crc_table();
+        assert_eq!(crc32(&table, &[&b"1234"[..], &b"56789"[..]]), 0xcbf43926);
+
+        let mut png = PngStream::new(vec![], 2, 2).unwrap();
+        png.write_row(&[255; 8]).unwrap();
+        png.write_row(&[0; 8]).unwrap();
+        let out = png.finish().unwrap();
+
+        assert_eq!(&out[1..4], b"PNG");
+        assert_eq!(&out[12..16], b"IHDR");
+        assert_eq!(&out[37..41], b"IDAT");
+        // empty IEND chunk and its well known checksum
+        assert_eq!(&out[out.len() - 12..], &[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xae, 0x42, 0x60, 0x82]);
+    }
+}
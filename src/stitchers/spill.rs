@@ -0,0 +1,175 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use ffmpeg::frame::Video;
+use ffmpeg::util::format::pixel::Pixel;
+use ffmpeg::util::color::range::Range;
+use error::Error;
+
+static CACHES : AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Frames moved out of memory by a stitcher, stored back to back in a temporary file.
+/// The file is created on the first spill and deleted on drop.
+pub struct SpillCache {
+    dir: PathBuf,
+    /// of the file once it is created
+    path: PathBuf,
+    file: Option<File>,
+    len: u64,
+    /// bytes of frames a stitcher may keep in memory
+    limit: usize
+}
+
+/// Where a frame was written and what is needed to restore it
+pub struct Spilled {
+    offset: u64,
+    format: Pixel,
+    width: u32,
+    height: u32,
+    pts: Option<i64>,
+    color_range: Range,
+    /// stride and length of each plane
+    planes: Vec<(usize, usize)>
+}
+
+impl SpillCache {
+    pub fn new(dir: &Path, limit: usize) -> SpillCache {
+        SpillCache {dir: dir.to_owned(), path: PathBuf::new(), file: None, len: 0, limit}
+    }
+
+    /// never opens an existing file, the temp dir may be shared with others
+    fn create(&mut self) -> Result<File, Error> {
+        loop {
+            let n = CACHES.fetch_add(1, Ordering::Relaxed);
+            let path = self.dir.join(format!("stitch-animation-{}-{}.spill", ::std::process::id(), n));
+            match OpenOptions::new().read(true).write(true).create_new(true).open(&path) {
+                Ok(file) => {
+                    self.path = path;
+                    return Ok(file);
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(Error::io(&path)(e))
+            }
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn store(&mut self, frame: &Video) -> Result<Spilled, Error> {
+        if self.file.is_none() {
+            let file = self.create()?;
+            self.file = Some(file);
+        }
+
+        let offset = self.len;
+        let mut planes = vec![];
+
+        {
+            let file = self.file.as_mut().unwrap();
+            file.seek(SeekFrom::Start(offset)).map_err(Error::io(&self.path))?;
+
+            for i in 0..frame.planes() {
+                let data = frame.data(i);
+                file.write_all(data).map_err(Error::io(&self.path))?;
+                planes.push((frame.stride(i), data.len()));
+            }
+        }
+
+        self.len += planes.iter().map(|&(_, len)| len as u64).sum::<u64>();
+
+        Ok(Spilled {
+            offset,
+            format: frame.format(),
+            width: frame.width(),
+            height: frame.height(),
+            pts: frame.pts(),
+            color_range: frame.color_range(),
+            planes
+        })
+    }
+
+    pub fn load(&self, spilled: &Spilled) -> Result<Video, Error> {
+        let mut file : &File = self.file.as_ref().ok_or("nothing was spilled")?;
+        file.seek(SeekFrom::Start(spilled.offset)).map_err(Error::io(&self.path))?;
+
+        let mut frame = Video::new(spilled.format, spilled.width, spilled.height);
+
+        for (i, &(stride, len)) in spilled.planes.iter().enumerate() {
+            let mut buf = vec![0u8; len];
+            file.read_exact(&mut buf).map_err(Error::io(&self.path))?;
+
+            let new_stride = frame.stride(i);
+            copy_rows(&buf, stride, frame.data_mut(i), new_stride);
+        }
+
+        frame.set_pts(spilled.pts);
+        frame.set_color_range(spilled.color_range);
+        Ok(frame)
+    }
+}
+
+/// a new allocation may be aligned differently, copy row by row then
+fn copy_rows(src: &[u8], src_stride: usize, dst: &mut [u8], dst_stride: usize) {
+    let row = ::std::cmp::min(src_stride, dst_stride);
+    for (row_in, row_out) in src.chunks(src_stride).zip(dst.chunks_mut(dst_stride)) {
+        let row = ::std::cmp::min(row, ::std::cmp::min(row_in.len(), row_out.len()));
+        row_out[..row].copy_from_slice(&row_in[..row]);
+    }
+}
+
+impl Drop for SpillCache {
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ffmpeg::frame::Video;
+    use ffmpeg::util::format::pixel::Pixel;
+    use super::{copy_rows, SpillCache};
+
+    #[test]
+    fn round_trip() {
+        let frame = |seed: u8| {
+            let mut v = Video::new(Pixel::YUV420P, 33, 7);
+            for i in 0..v.planes() {
+                for (j, b) in v.data_mut(i).iter_mut().enumerate() {
+                    *b = (j as u8).wrapping_mul(seed).wrapping_add(i as u8);
+                }
+            }
+            v.set_pts(Some(seed as i64));
+            v
+        };
+
+        let mut cache = SpillCache::new(&::std::env::temp_dir(), 0);
+        let spilled: Vec<_> = (1..4).map(|seed| cache.store(&frame(seed)).unwrap()).collect();
+        let path = cache.path.clone();
+        assert!(path.exists());
+
+        for (seed, s) in (1..4).zip(spilled.iter()).rev() {
+            let (original, loaded) = (frame(seed), cache.load(s).unwrap());
+            assert_eq!((loaded.format(), loaded.width(), loaded.height(), loaded.pts()), (Pixel::YUV420P, 33, 7, Some(seed as i64)));
+            for i in 0..original.planes() {
+                assert_eq!(original.data(i), loaded.data(i));
+            }
+        }
+
+        drop(cache);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn relayout() {
+        // 3 rows of 5 bytes, stored with a stride of 8 and loaded with one of 6
+        let src: Vec<u8> = (0..24).collect();
+        let mut dst = vec![0u8; 18];
+        copy_rows(&src, 8, &mut dst, 6);
+        assert_eq!(dst, vec![0, 1, 2, 3, 4, 5, 8, 9, 10, 11, 12, 13, 16, 17, 18, 19, 20, 21]);
+    }
+}
@@ -0,0 +1,128 @@
+use std::fmt::Write;
+use serde_json;
+use error::Error;
+
+// the camera move of a sequence, i.e. where each frame sits on the composite, for recreating it
+// in compositing tools. positions are the top left corner of the frame, in pixels of the written
+// composite unless noted otherwise.
+
+/// Position of a frame on the composite
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct Key {
+    pub frame: u32,
+    pub pts: Option<i64>,
+    /// seconds since the first frame of the sequence, None without timestamps
+    pub time: Option<f64>,
+    /// on the canvas, before aspect correction
+    pub canvas_x: isize,
+    pub canvas_y: isize,
+    pub x: f64,
+    pub y: f64
+}
+
+#[derive(Serialize, Debug)]
+pub struct Trajectory {
+    pub name: String,
+    /// file name of the background composite
+    pub composite: String,
+    pub sar: (i32, i32),
+    /// size before aspect correction
+    pub canvas_size: (u32, u32),
+    pub composite_size: (u32, u32),
+    /// the view of the camera
+    pub frame_size: (u32, u32),
+    pub keys: Vec<Key>
+}
+
+impl Trajectory {
+    pub fn csv(&self) -> String {
+        let mut out = String::from("frame,pts,time,canvas_x,canvas_y,x,y\n");
+        for k in &self.keys {
+            let pts = k.pts.map(|p| p.to_string()).unwrap_or(String::new());
+            let time = k.time.map(|t| format!("{:.6}", t)).unwrap_or(String::new());
+            writeln!(out, "{},{},{},{},{},{:.2},{:.2}", k.frame, pts, time, k.canvas_x, k.canvas_y, k.x, k.y).unwrap();
+        }
+        out
+    }
+
+    pub fn json(&self) -> Result<String, Error> {
+        serde_json::to_string_pretty(self).map_err(|e| format!("{}: {}", self.name, e).into())
+    }
+
+    /// the shortest interval between frames, held frames only make some of them longer. 24 without timestamps
+    pub fn frame_rate(&self) -> f64 {
+        let shortest = self.keys.windows(2).filter_map(|w| match (w[0].time, w[1].time) {
+            (Some(a), Some(b)) if b > a => Some(b - a),
+            _ => None
+        }).fold(None, |min: Option<f64>, d| Some(min.map(|m| m.min(d)).unwrap_or(d)));
+
+        shortest.map(|d| 1.0 / d).unwrap_or(24.0)
+    }
+
+    /// ExtendScript for After Effects. creates a comp of the frame size and moves the composite through it
+    /// with hold keyframes, one per frame. expects the composite next to the script
+    pub fn after_effects(&self) -> String {
+        let rate = self.frame_rate();
+        let first = self.keys.first().map(|k| k.frame).unwrap_or(0);
+        let time = |k: &Key| k.time.unwrap_or((k.frame - first) as f64 / rate);
+        let duration = self.keys.last().map(|k| time(k)).unwrap_or(0.0) + 1.0 / rate;
+        // JSON strings are valid JavaScript string literals
+        let quote = |s: &str| serde_json::to_string(s).unwrap();
+
+        let mut out = String::new();
+        writeln!(out, "// camera move of {}, written by stitch-animation. run it with File > Scripts > Run Script File", self.name).unwrap();
+        writeln!(out, "(function () {{").unwrap();
+        writeln!(out, "    var keys = [").unwrap();
+        for (i, k) in self.keys.iter().enumerate() {
+            let sep = if i + 1 < self.keys.len() { "," } else { "" };
+            writeln!(out, "        [{:.6}, {:.2}, {:.2}]{}", time(k), k.x, k.y, sep).unwrap();
+        }
+        writeln!(out, "    ];").unwrap();
+        writeln!(out, "    var file = new File(new File($.fileName).parent.fsName + \"/\" + {});", quote(&self.composite)).unwrap();
+        writeln!(out, "    if (!file.exists) {{").unwrap();
+        writeln!(out, "        alert(\"composite not found: \" + file.fsName);").unwrap();
+        writeln!(out, "        return;").unwrap();
+        writeln!(out, "    }}").unwrap();
+        writeln!(out, "    app.beginUndoGroup({});", quote(&format!("{} camera", self.name))).unwrap();
+        writeln!(out, "    var footage = app.project.importFile(new ImportOptions(file));").unwrap();
+        writeln!(out, "    var comp = app.project.items.addComp({}, {}, {}, 1, {:.6}, {:.3});",
+                 quote(&self.name), self.frame_size.0, self.frame_size.1, duration, rate).unwrap();
+        writeln!(out, "    var layer = comp.layers.add(footage);").unwrap();
+        writeln!(out, "    layer.anchorPoint.setValue([0, 0]);").unwrap();
+        writeln!(out, "    for (var i = 0; i < keys.length; i++) {{").unwrap();
+        writeln!(out, "        var k = layer.position.addKey(keys[i][0]);").unwrap();
+        writeln!(out, "        layer.position.setValueAtKey(k, [-keys[i][1], -keys[i][2]]);").unwrap();
+        writeln!(out, "        layer.position.setInterpolationTypeAtKey(k, KeyframeInterpolationType.HOLD);").unwrap();
+        writeln!(out, "    }}").unwrap();
+        writeln!(out, "    app.endUndoGroup();").unwrap();
+        writeln!(out, "}})();").unwrap();
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Key, Trajectory};
+
+    #[test]
+    fn export() {
+        let key = |frame: u32, time: f64, x: isize| Key {frame, pts: Some(frame as i64 * 1001), time: Some(time), canvas_x: x, canvas_y: 0, x: x as f64 * 2.0, y: 0.0};
+        let trajectory = Trajectory {
+            name: "000010_00h00m00s417".to_owned(),
+            composite: "000010_00h00m00s417_lin.png".to_owned(),
+            sar: (2, 1),
+            canvas_size: (30, 10),
+            composite_size: (60, 10),
+            frame_size: (40, 10),
+            // a held frame between the second and third key
+            keys: vec![key(10, 0.0, 0), key(11, 0.5, 5), key(13, 1.5, 10)]
+        };
+
+        assert_eq!(trajectory.frame_rate(), 2.0);
+        assert_eq!(trajectory.csv().lines().nth(2), Some("11,11011,0.500000,5,0,10.00,0.00"));
+
+        let script = trajectory.after_effects();
+        assert!(script.contains("[1.500000, 20.00, 0.00]\n    ];"));
+        assert!(script.contains("addComp(\"000010_00h00m00s417\", 40, 10, 1, 2.000000, 2.000);"));
+    }
+}